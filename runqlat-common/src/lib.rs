@@ -1,5 +1,22 @@
 #![no_std]
 
+/// `PID` map flag: also record per-thread histograms in `THREAD_HIST` for the process.
+pub const TRACK_THREADS: u8 = 1 << 0;
+
+/// `PID` map flag: the process was added by the eBPF program because its comm is in `COMM`.
+/// Also the `MATCH` map bit enabling that matching.
+pub const COMM_MATCH: u8 = 1 << 1;
+
+/// `PID` map flag: the process was added by the eBPF program because its user is in `UID`.
+/// Also the `MATCH` map bit enabling that matching.
+pub const UID_MATCH: u8 = 1 << 2;
+
+/// Length of a task name including the trailing NUL, see `TASK_COMM_LEN` in linux/sched.h.
+pub const TASK_COMM_LEN: usize = 16;
+
+/// Key of the `COMM` map: task name padded with NULs.
+pub type Comm = [u8; TASK_COMM_LEN];
+
 /// Max number of slots in histograms.
 /// log2(1_000_000) ~= 19.93, so 20 slots for 0..1s in us
 pub const MAX_SLOTS: usize = 20;
@@ -18,3 +35,40 @@ pub const MAX_SLOTS: usize = 20;
 //        512 -> 1023       : 5        |                                        |
 //       1024 -> 2047       : 27       |*                                       |
 pub type Histogram = [u32; MAX_SLOTS];
+
+/// Layout of the maps shared by userspace and the eBPF programs, embedded in the eBPF object as
+/// the `RUNQLAT_ABI` global and checked by userspace before loading it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Abi {
+    /// Bumped on changes of the maps that the sizes below do not reveal, e.g. the meaning of a
+    /// flag or the order of fields.
+    pub version: u32,
+    /// [`MAX_SLOTS`].
+    pub max_slots: u32,
+    /// Size of [`Histogram`].
+    pub histogram_size: u32,
+    /// Size of [`Comm`].
+    pub comm_size: u32,
+}
+
+impl Abi {
+    /// Layout of this version of runqlat.
+    pub const CURRENT: Abi = Abi {
+        version: 1,
+        max_slots: MAX_SLOTS as u32,
+        histogram_size: size_of::<Histogram>() as u32,
+        comm_size: size_of::<Comm>() as u32,
+    };
+}
+
+/// Inclusive range of latencies in us counted by `slot`.
+///
+/// The last slot also counts every latency above its upper bound.
+pub const fn slot_bounds(slot: usize) -> (u64, u64) {
+    if slot == 0 {
+        (0, 1)
+    } else {
+        (1 << slot, (1 << (slot + 1)) - 1)
+    }
+}
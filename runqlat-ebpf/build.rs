@@ -1,5 +1,19 @@
+use std::{env, fs, path::PathBuf};
+
+use aya_tool::generate::{InputFile, generate};
 use which::which;
 
+#[allow(dead_code)]
+#[path = "../runqlat/src/btf.rs"]
+mod btf;
+
+/// BTF to generate the kernel type bindings from, e.g. `/sys/kernel/btf/vmlinux` of the kernel
+/// the programs will run on, instead of using the committed ones.
+const VMLINUX_BTF_ENV: &str = "RUNQLAT_VMLINUX_BTF";
+
+/// Kernel types the programs read.
+const TYPES: [&str; 2] = ["task_struct", "cred"];
+
 /// Building this crate has an undeclared dependency on the `bpf-linker` binary. This would be
 /// better expressed by [artifact-dependencies][bindeps] but issues such as
 /// https://github.com/rust-lang/cargo/issues/12385 make their use impractical for the time being.
@@ -14,4 +28,55 @@ use which::which;
 fn main() {
     let bpf_linker = which("bpf-linker").unwrap();
     println!("cargo:rerun-if-changed={}", bpf_linker.to_str().unwrap());
+
+    select_bindings();
+}
+
+/// Includes the kernel type bindings generated from the BTF at [`VMLINUX_BTF_ENV`], or else the
+/// committed ones of the architecture the programs run on, which aya-build passes as
+/// `bpf_target_arch`, see src/vmlinux/mod.rs. Fails for architectures without committed bindings.
+fn select_bindings() {
+    println!("cargo::rustc-check-cfg=cfg(vmlinux_legacy_state)");
+    println!("cargo:rerun-if-env-changed={VMLINUX_BTF_ENV}");
+
+    let (bindings, legacy_state) = if let Some(btf) = env::var_os(VMLINUX_BTF_ENV) {
+        let btf = PathBuf::from(btf);
+        println!("cargo:rerun-if-changed={}", btf.display());
+        let code = generate(InputFile::Btf(btf.clone()), &TYPES, &[])
+            .unwrap_or_else(|e| panic!("failed to generate bindings from {}: {e}", btf.display()));
+        let bindings = PathBuf::from(env::var("OUT_DIR").unwrap()).join("vmlinux.rs");
+        fs::write(&bindings, code).unwrap();
+        // kernels before 5.14 have `long state` instead of `__state`
+        let legacy_state = btf::Btf::from_file(&btf)
+            .unwrap_or_else(|e| panic!("{e:#}"))
+            .member("task_struct", "__state")
+            .is_err();
+        (bindings, legacy_state)
+    } else {
+        let arch = env::var("CARGO_CFG_BPF_TARGET_ARCH").unwrap_or_else(|_| {
+            // built directly rather than by aya-build
+            let host = env::var("HOST").unwrap();
+            host.split('-').next().unwrap().to_owned()
+        });
+        let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/vmlinux");
+        // also reruns when bindings are added
+        println!("cargo:rerun-if-changed={}", dir.display());
+
+        let bindings = dir.join(format!("{arch}.rs"));
+        if !bindings.exists() {
+            panic!(
+                "no kernel type bindings for {arch} in {}, generate them on a machine of that \
+                 architecture, see src/vmlinux/mod.rs, or set {VMLINUX_BTF_ENV} to build for a \
+                 specific kernel",
+                dir.display()
+            );
+        }
+        // committed bindings are of kernels with `__state`, see runqlat/build.rs
+        (bindings, false)
+    };
+
+    println!("cargo::rustc-env=VMLINUX_BINDINGS={}", bindings.display());
+    if legacy_state {
+        println!("cargo::rustc-cfg=vmlinux_legacy_state");
+    }
 }
@@ -16,29 +16,116 @@
 mod vmlinux;
 
 use aya_ebpf::{
+    EbpfContext as _,
+    bindings::BPF_NOEXIST,
     helpers::{bpf_ktime_get_ns, bpf_probe_read_kernel},
-    macros::{map, raw_tracepoint},
-    maps::HashMap,
-    programs::RawTracePointContext,
+    macros::{btf_tracepoint, fentry, kprobe, map, raw_tracepoint, tracepoint},
+    maps::{Array, HashMap, LruHashMap},
+    programs::{
+        BtfTracePointContext, FEntryContext, ProbeContext, RawTracePointContext, TracePointContext,
+    },
 };
 
-use runqlat_common::{Histogram, MAX_SLOTS};
-use vmlinux::task_struct;
+use runqlat_common::{Abi, COMM_MATCH, Comm, Histogram, MAX_SLOTS, TRACK_THREADS, UID_MATCH};
+use vmlinux::{cred, offsets, task_struct};
 
 /// Max number of tracked processes and threads
 const MAX_ENTRIES: u32 = 2048;
 
-const TASK_RUNNING: u32 = 0;
+/// Max number of task names matched in the kernel
+const MAX_COMMS: u32 = 64;
+
+/// Max number of user ids matched in the kernel
+const MAX_UIDS: u32 = 64;
+
+/// Max number of threads mapped to their process for the tracepoint programs
+const MAX_THREADS: u32 = 16384;
+
+const TASK_RUNNING: u64 = 0;
+
+/// Offset of `pid` in `sched_process_free` events, whose `sched_process_template` layout has not
+/// changed since it was introduced.
+const FREE_PID_OFFSET: usize = 24;
+
+/// Sleeping states reported in `sched_switch.prev_state`. Preempted tasks are reported with a
+/// bit above these (`TASK_REPORT_MAX`), so a task is still runnable if none of them is set.
+const TASK_REPORT: u64 = 0xff;
+
+/// Layout of the maps this object was built with, checked by userspace before loading it. Never
+/// read by the programs, so kept with `#[used]`.
+#[unsafe(no_mangle)]
+#[used]
+static RUNQLAT_ABI: Abi = Abi::CURRENT;
+
+// Offsets of kernel struct fields read by all programs but the `tp_*` ones, set by userspace from
+// the running kernel's BTF. Defaults are the offsets in the committed bindings.
+#[unsafe(no_mangle)]
+static TASK_PID_OFFSET: u32 = offsets::TASK_PID;
+#[unsafe(no_mangle)]
+static TASK_TGID_OFFSET: u32 = offsets::TASK_TGID;
+#[unsafe(no_mangle)]
+static TASK_STATE_OFFSET: u32 = offsets::TASK_STATE;
+/// Size of the state field: 4 for `__state`, 8 for the `long state` of kernels before 5.14.
+#[unsafe(no_mangle)]
+static TASK_STATE_SIZE: u32 = offsets::TASK_STATE_SIZE;
+#[unsafe(no_mangle)]
+static TASK_COMM_OFFSET: u32 = offsets::TASK_COMM;
+#[unsafe(no_mangle)]
+static TASK_GROUP_LEADER_OFFSET: u32 = offsets::TASK_GROUP_LEADER;
+#[unsafe(no_mangle)]
+static TASK_REAL_CRED_OFFSET: u32 = offsets::TASK_REAL_CRED;
+#[unsafe(no_mangle)]
+static CRED_UID_OFFSET: u32 = offsets::CRED_UID;
+
+/// Position of `struct task_struct *p` in the arguments of `ttwu_do_wakeup`, which lost its
+/// leading `struct rq *rq` in Linux 6.3. Defaults to the kernels before, like the bindings.
+#[unsafe(no_mangle)]
+static WAKEUP_TASK_ARG: u32 = 1;
+
+// Offsets of tracepoint fields, set by userspace from the event formats in tracefs.
+// Defaults are the layout since Linux 4.x on 64-bit architectures.
+#[unsafe(no_mangle)]
+static WAKEUP_PID_OFFSET: u32 = 24;
+#[unsafe(no_mangle)]
+static SWITCH_PREV_PID_OFFSET: u32 = 24;
+#[unsafe(no_mangle)]
+static SWITCH_PREV_STATE_OFFSET: u32 = 32;
+#[unsafe(no_mangle)]
+static SWITCH_PREV_STATE_SIZE: u32 = 8;
+#[unsafe(no_mangle)]
+static SWITCH_NEXT_PID_OFFSET: u32 = 56;
 
 // NOTE:
 // tutorial https://eunomia.dev/en/tutorials/9-runqlat/
 // pid vs tgid in task_struct https://marselester.com/linux-process.html
 
 /// Tracked processes.
-/// tgid (process id) -> tracked
+/// tgid (process id) -> flags (`TRACK_THREADS`, `COMM_MATCH`, `UID_MATCH`)
 #[map(name = "PID")]
 static mut PID: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(MAX_ENTRIES, 0);
 
+/// Task names whose processes are added to `PID` as soon as they run or exec.
+/// comm (NUL padded) -> unused
+#[map(name = "COMM")]
+static mut COMM: HashMap<Comm, u8> = HashMap::<Comm, u8>::with_max_entries(MAX_COMMS, 0);
+
+/// Kinds of matching enabled by userspace, `COMM_MATCH` while `COMM` has entries and
+/// `UID_MATCH` while `UID` has entries, so that untracked tasks are not matched for nothing on
+/// every wakeup and switch.
+/// 0 -> kinds (`COMM_MATCH` | `UID_MATCH`)
+#[map(name = "MATCH")]
+static mut MATCH: Array<u8> = Array::<u8>::with_max_entries(1, 0);
+
+/// Real user ids whose processes are added to `PID` as soon as they run or exec.
+/// uid -> unused
+#[map(name = "UID")]
+static mut UID: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(MAX_UIDS, 0);
+
+/// Process of threads, for the tracepoint programs whose events only carry thread ids.
+/// pid (thread id) -> tgid (process id)
+#[map(name = "TGID")]
+static mut TGID: LruHashMap<u32, u32> = LruHashMap::<u32, u32>::with_max_entries(MAX_THREADS, 0);
+
 /// Start timestamps of threads.
 /// pid (thread id) -> start timestamp (ns)
 #[map(name = "START")]
@@ -50,27 +137,17 @@ static mut START: HashMap<u32, u64> = HashMap::<u32, u64>::with_max_entries(MAX_
 static mut HIST: HashMap<u32, Histogram> =
     HashMap::<u32, Histogram>::with_max_entries(MAX_ENTRIES, 0);
 
+/// Histograms of run queue latencies of threads of processes tracked with `TRACK_THREADS`.
+/// pid (thread id) -> histogram of run queue latencies counts in log2 buckets (us)
+#[map(name = "THREAD_HIST")]
+static mut THREAD_HIST: HashMap<u32, Histogram> =
+    HashMap::<u32, Histogram>::with_max_entries(MAX_ENTRIES, 0);
+
 // https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L178
 #[raw_tracepoint(tracepoint = "sched_wakeup")]
 pub fn sched_wakeup(ctx: RawTracePointContext) -> i32 {
     let task: *const task_struct = unsafe { ctx.arg(0) };
-    if task.is_null() {
-        return 0;
-    }
-
-    let tgid = match unsafe { bpf_probe_read_kernel(&(*task).tgid) } {
-        Ok(tgid) => tgid as u32,
-        Err(_) => return 0,
-    };
-    if unsafe { PID.get(&tgid).is_none() } {
-        return 0;
-    }
-
-    let pid = match unsafe { bpf_probe_read_kernel(&(*task).pid) } {
-        Ok(pid) => pid as u32,
-        Err(_) => return 0,
-    };
-    save_start_ts(pid);
+    let _ = try_wakeup(task);
     0
 }
 
@@ -78,24 +155,24 @@ pub fn sched_wakeup(ctx: RawTracePointContext) -> i32 {
 #[raw_tracepoint(tracepoint = "sched_wakeup_new")]
 pub fn sched_wakeup_new(ctx: RawTracePointContext) -> i32 {
     let task: *const task_struct = unsafe { ctx.arg(0) };
+    let _ = try_wakeup(task);
+    0
+}
+
+#[inline(always)]
+fn try_wakeup(task: *const task_struct) -> Result<(), i64> {
     if task.is_null() {
-        return 0;
+        return Ok(());
     }
 
-    let tgid = match unsafe { bpf_probe_read_kernel(&(*task).tgid) } {
-        Ok(tgid) => tgid as u32,
-        Err(_) => return 0,
-    };
-    if unsafe { PID.get(&tgid).is_none() } {
-        return 0;
+    let tgid = unsafe { read_field::<i32, _>(task, &TASK_TGID_OFFSET)? as u32 };
+    if tracked_flags(task, tgid).is_none() {
+        return Ok(());
     }
 
-    let pid = match unsafe { bpf_probe_read_kernel(&(*task).pid) } {
-        Ok(pid) => pid as u32,
-        Err(_) => return 0,
-    };
+    let pid = unsafe { read_field::<i32, _>(task, &TASK_PID_OFFSET)? as u32 };
     save_start_ts(pid);
-    0
+    Ok(())
 }
 
 // https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L222
@@ -114,35 +191,316 @@ fn try_sched_switch(ctx: RawTracePointContext) -> Result<(), i64> {
     }
 
     // NOTE: bpf_probe_read_kernel used to read fields from kernel memory of raw_tracepoint args
-    let prev_pid = unsafe { bpf_probe_read_kernel(&(*prev).tgid)? as u32 };
-    let prev_tgid = unsafe { bpf_probe_read_kernel(&(*prev).tgid)? as u32 };
-    let prev_state = unsafe { bpf_probe_read_kernel(&(*prev).__state)? };
+    let prev_pid = unsafe { read_field::<i32, _>(prev, &TASK_PID_OFFSET)? as u32 };
+    let prev_tgid = unsafe { read_field::<i32, _>(prev, &TASK_TGID_OFFSET)? as u32 };
+    let prev_state = read_state(prev)?;
+    switch_out(prev, prev_pid, prev_tgid, prev_state);
 
-    // if prev.state running and prev.tgid tracked -> save start_ts of prev.pid
-    if prev_state == TASK_RUNNING && unsafe { PID.get(&prev_tgid).is_some() } {
-        save_start_ts(prev_pid);
+    let next_tgid = unsafe { read_field::<i32, _>(next, &TASK_TGID_OFFSET)? as u32 };
+
+    // if next.tgid not tracked -> return
+    if unsafe { PID.get(&next_tgid).is_none() } {
+        return Ok(());
     }
 
-    let next_tgid = unsafe { bpf_probe_read_kernel(&(*next).tgid)? as u32 };
+    let next_pid = unsafe { read_field::<i32, _>(next, &TASK_PID_OFFSET)? as u32 };
+    record_latency(next_pid, next_tgid);
+    Ok(())
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L178
+#[btf_tracepoint(function = "sched_wakeup")]
+pub fn tp_btf_sched_wakeup(ctx: BtfTracePointContext) -> i32 {
+    let task: *const task_struct = unsafe { ctx.arg(0) };
+    btf_wakeup(task);
+    0
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L185
+#[btf_tracepoint(function = "sched_wakeup_new")]
+pub fn tp_btf_sched_wakeup_new(ctx: BtfTracePointContext) -> i32 {
+    let task: *const task_struct = unsafe { ctx.arg(0) };
+    btf_wakeup(task);
+    0
+}
+
+#[inline(always)]
+fn btf_wakeup(task: *const task_struct) {
+    // NOTE: arguments of tp_btf programs are trusted, so their fields are read directly
+    let tgid = unsafe { load_field::<i32, _>(task, &TASK_TGID_OFFSET) as u32 };
+    if tracked_flags(task, tgid).is_none() {
+        return;
+    }
+
+    let pid = unsafe { load_field::<i32, _>(task, &TASK_PID_OFFSET) as u32 };
+    save_start_ts(pid);
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L222
+#[btf_tracepoint(function = "sched_switch")]
+pub fn tp_btf_sched_switch(ctx: BtfTracePointContext) -> i32 {
+    let prev: *const task_struct = unsafe { ctx.arg(1) };
+    let next: *const task_struct = unsafe { ctx.arg(2) };
+
+    let prev_pid = unsafe { load_field::<i32, _>(prev, &TASK_PID_OFFSET) as u32 };
+    let prev_tgid = unsafe { load_field::<i32, _>(prev, &TASK_TGID_OFFSET) as u32 };
+    let prev_state = load_state(prev);
+    switch_out(prev, prev_pid, prev_tgid, prev_state);
 
     // if next.tgid not tracked -> return
+    let next_tgid = unsafe { load_field::<i32, _>(next, &TASK_TGID_OFFSET) as u32 };
     if unsafe { PID.get(&next_tgid).is_none() } {
+        return 0;
+    }
+
+    let next_pid = unsafe { load_field::<i32, _>(next, &TASK_PID_OFFSET) as u32 };
+    record_latency(next_pid, next_tgid);
+    0
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L148
+#[tracepoint]
+pub fn tp_sched_wakeup(ctx: TracePointContext) -> u32 {
+    let _ = try_tp_sched_wakeup(&ctx);
+    0
+}
+
+#[tracepoint]
+pub fn tp_sched_wakeup_new(ctx: TracePointContext) -> u32 {
+    let _ = try_tp_sched_wakeup(&ctx);
+    0
+}
+
+#[inline(always)]
+fn try_tp_sched_wakeup(ctx: &TracePointContext) -> Result<(), i64> {
+    let pid = unsafe { ctx.read_at::<i32>(global(&WAKEUP_PID_OFFSET) as usize)? as u32 };
+
+    // threads not mapped to a process yet are not tracked
+    let tgid = match unsafe { TGID.get(&pid) } {
+        Some(tgid) => *tgid,
+        None => return Ok(()),
+    };
+    if unsafe { PID.get(&tgid).is_some() } {
+        save_start_ts(pid);
+    }
+    Ok(())
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L222
+#[tracepoint]
+pub fn tp_sched_switch(ctx: TracePointContext) -> u32 {
+    let _ = try_tp_sched_switch(&ctx);
+    0
+}
+
+#[inline(always)]
+fn try_tp_sched_switch(ctx: &TracePointContext) -> Result<(), i64> {
+    let prev_pid = unsafe { ctx.read_at::<i32>(global(&SWITCH_PREV_PID_OFFSET) as usize)? as u32 };
+    let prev_state_offset = global(&SWITCH_PREV_STATE_OFFSET) as usize;
+    let prev_state = if global(&SWITCH_PREV_STATE_SIZE) == 8 {
+        unsafe { ctx.read_at::<u64>(prev_state_offset)? }
+    } else {
+        unsafe { ctx.read_at::<u32>(prev_state_offset)? as u64 }
+    };
+
+    // the event fires in the context of prev, so its tgid is the current one
+    let prev_tgid = ctx.tgid();
+    if unsafe { PID.get(&prev_tgid).is_some() } {
+        // map threads started after their process was tracked
+        let _ = unsafe { TGID.insert(&prev_pid, &prev_tgid, 0) };
+        if prev_state & TASK_REPORT == 0 {
+            save_start_ts(prev_pid);
+        }
+    }
+
+    let next_pid = unsafe { ctx.read_at::<i32>(global(&SWITCH_NEXT_PID_OFFSET) as usize)? as u32 };
+    let next_tgid = match unsafe { TGID.get(&next_pid) } {
+        Some(tgid) => *tgid,
+        None => return Ok(()),
+    };
+    record_latency(next_pid, next_tgid);
+    Ok(())
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/A/ident/ttwu_do_wakeup
+#[fentry(function = "ttwu_do_wakeup")]
+pub fn fentry_ttwu_do_wakeup(ctx: FEntryContext) -> i32 {
+    let task: *const task_struct = if global(&WAKEUP_TASK_ARG) == 1 {
+        unsafe { ctx.arg(1) }
+    } else {
+        unsafe { ctx.arg(0) }
+    };
+    btf_wakeup(task);
+    0
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/A/ident/ttwu_do_activate
+// Hooked instead of ttwu_do_wakeup where the latter is inlined.
+#[fentry(function = "ttwu_do_activate")]
+pub fn fentry_ttwu_do_activate(ctx: FEntryContext) -> i32 {
+    let task: *const task_struct = unsafe { ctx.arg(1) };
+    btf_wakeup(task);
+    0
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/A/ident/wake_up_new_task
+#[fentry(function = "wake_up_new_task")]
+pub fn fentry_wake_up_new_task(ctx: FEntryContext) -> i32 {
+    let task: *const task_struct = unsafe { ctx.arg(0) };
+    btf_wakeup(task);
+    0
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/A/ident/finish_task_switch
+#[fentry(function = "finish_task_switch")]
+pub fn fentry_finish_task_switch(ctx: FEntryContext) -> i32 {
+    let prev: *const task_struct = unsafe { ctx.arg(0) };
+
+    // NOTE: arguments of fentry programs are trusted, so their fields are read directly
+    let prev_pid = unsafe { load_field::<i32, _>(prev, &TASK_PID_OFFSET) as u32 };
+    let prev_tgid = unsafe { load_field::<i32, _>(prev, &TASK_TGID_OFFSET) as u32 };
+    switch_out(prev, prev_pid, prev_tgid, load_state(prev));
+
+    // the switch is done, so next is the current task
+    record_latency(ctx.pid(), ctx.tgid());
+    0
+}
+
+#[kprobe]
+pub fn kprobe_ttwu_do_wakeup(ctx: ProbeContext) -> u32 {
+    let task: Option<*const task_struct> = if global(&WAKEUP_TASK_ARG) == 1 {
+        ctx.arg(1)
+    } else {
+        ctx.arg(0)
+    };
+    if let Some(task) = task {
+        let _ = try_wakeup(task);
+    }
+    0
+}
+
+#[kprobe]
+pub fn kprobe_ttwu_do_activate(ctx: ProbeContext) -> u32 {
+    if let Some(task) = ctx.arg::<*const task_struct>(1) {
+        let _ = try_wakeup(task);
+    }
+    0
+}
+
+#[kprobe]
+pub fn kprobe_wake_up_new_task(ctx: ProbeContext) -> u32 {
+    if let Some(task) = ctx.arg::<*const task_struct>(0) {
+        let _ = try_wakeup(task);
+    }
+    0
+}
+
+#[kprobe]
+pub fn kprobe_finish_task_switch(ctx: ProbeContext) -> u32 {
+    let _ = try_kprobe_finish_task_switch(&ctx);
+    0
+}
+
+#[inline(always)]
+fn try_kprobe_finish_task_switch(ctx: &ProbeContext) -> Result<(), i64> {
+    let Some(prev) = ctx.arg::<*const task_struct>(0) else {
+        return Ok(());
+    };
+    if prev.is_null() {
         return Ok(());
     }
 
-    let next_pid = unsafe { bpf_probe_read_kernel(&(*next).pid)? as u32 };
+    let prev_pid = unsafe { read_field::<i32, _>(prev, &TASK_PID_OFFSET)? as u32 };
+    let prev_tgid = unsafe { read_field::<i32, _>(prev, &TASK_TGID_OFFSET)? as u32 };
+    switch_out(prev, prev_pid, prev_tgid, read_state(prev)?);
+
+    // the switch is done, so next is the current task
+    record_latency(ctx.pid(), ctx.tgid());
+    Ok(())
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/source/include/trace/events/sched.h#L397
+#[raw_tracepoint(tracepoint = "sched_process_exec")]
+pub fn sched_process_exec(ctx: RawTracePointContext) -> i32 {
+    let _ = try_sched_process_exec(ctx);
+    0
+}
+
+#[inline(always)]
+fn try_sched_process_exec(ctx: RawTracePointContext) -> Result<(), i64> {
+    let task: *const task_struct = unsafe { ctx.arg(0) };
+    if task.is_null() {
+        return Ok(());
+    }
+
+    // comm was already set to the new executable's name, and task is the thread group leader
+    let tgid = unsafe { read_field::<i32, _>(task, &TASK_TGID_OFFSET)? as u32 };
+    if matching(COMM_MATCH) {
+        let comm = unsafe { read_field::<Comm, _>(task, &TASK_COMM_OFFSET)? };
+        if match_comm(tgid, &comm).is_some() {
+            return Ok(());
+        }
+    }
+    match_uid(task, tgid);
+    Ok(())
+}
+
+// https://elixir.bootlin.com/linux/v6.2.16/A/ident/sched_process_free
+#[tracepoint]
+pub fn sched_process_free(ctx: TracePointContext) -> u32 {
+    let _ = try_sched_process_free(&ctx);
+    0
+}
+
+/// Untracks processes added by the eBPF program once they are reaped, so their pid is not
+/// tracked when reused. Processes tracked by userspace are untracked by userspace.
+#[inline(always)]
+fn try_sched_process_free(ctx: &TracePointContext) -> Result<(), i64> {
+    // a thread id is only the tgid of a process in PID if the thread is its group leader, which
+    // is freed last
+    let pid = unsafe { ctx.read_at::<i32>(FREE_PID_OFFSET)? as u32 };
+    let flags = match unsafe { PID.get(&pid) } {
+        Some(flags) => *flags,
+        None => return Ok(()),
+    };
+    if flags & (COMM_MATCH | UID_MATCH) != 0 {
+        let _ = unsafe { PID.remove(&pid) };
+    }
+    Ok(())
+}
+
+// -- helpers --
+
+/// Saves the start timestamp of `prev` if it is tracked and was switched out while runnable,
+/// i.e. preempted.
+#[inline(always)]
+fn switch_out(prev: *const task_struct, prev_pid: u32, prev_tgid: u32, prev_state: u64) {
+    // if prev.state running and prev.tgid tracked -> save start_ts of prev.pid
+    if prev_state == TASK_RUNNING && tracked_flags(prev, prev_tgid).is_some() {
+        save_start_ts(prev_pid);
+    }
+}
+
+/// Records the run queue latency of thread `next_pid` of process `next_tgid` being switched in,
+/// if it is tracked and its wakeup was seen.
+#[inline(always)]
+fn record_latency(next_pid: u32, next_tgid: u32) {
+    let next_flags = match unsafe { PID.get(&next_tgid) } {
+        Some(flags) => *flags,
+        None => return,
+    };
 
     // get next.pid saved start_ts
     let start_ts = match unsafe { START.get(&next_pid) } {
         Some(ts) => *ts,
-        None => return Ok(()),
+        None => return,
     };
 
     // calculate delta_us = now_ts - start_ts
     let now_ts = unsafe { bpf_ktime_get_ns() };
     if now_ts < start_ts {
         let _ = unsafe { START.remove(&next_pid) };
-        return Ok(());
+        return;
     }
     let delta_us = (now_ts - start_ts) / 1000;
 
@@ -152,24 +510,114 @@ fn try_sched_switch(ctx: RawTracePointContext) -> Result<(), i64> {
         slot = MAX_SLOTS - 1;
     }
 
-    // increment histogram slot of next.tgid
-    if let Some(hist) = unsafe { HIST.get_ptr_mut(&next_tgid) } {
-        unsafe {
-            (*hist)[slot] = (*hist)[slot].saturating_add(1);
-        }
-    } else {
-        let mut hist = [0; MAX_SLOTS];
-        hist[slot] = 1;
-        let _ = unsafe { HIST.insert(&next_tgid, &hist, 0) };
+    // increment histogram slot of next.tgid (and next.pid if its threads are tracked)
+    increment_slot(unsafe { &HIST }, next_tgid, slot);
+    if next_flags & TRACK_THREADS != 0 {
+        increment_slot(unsafe { &THREAD_HIST }, next_pid, slot);
     }
 
     // remove next.pid start_ts
     let _ = unsafe { START.remove(&next_pid) };
+}
 
-    Ok(())
+/// Flags of the process of `task` in `PID`. Untracked processes whose name is in `COMM` or
+/// whose user is in `UID` are added to `PID` first.
+#[inline(always)]
+fn tracked_flags(task: *const task_struct, tgid: u32) -> Option<u8> {
+    if let Some(flags) = unsafe { PID.get(&tgid) } {
+        return Some(*flags);
+    }
+    let comm = matching(COMM_MATCH)
+        .then(|| match_leader_comm(task, tgid))
+        .flatten();
+    comm.or_else(|| match_uid(task, tgid))
 }
 
-// -- helpers --
+/// Whether userspace enabled matching of `kind`, see `MATCH`.
+#[inline(always)]
+fn matching(kind: u8) -> bool {
+    unsafe { MATCH.get(0) }.is_some_and(|&kinds| kinds & kind != 0)
+}
+
+/// Matches the process name, i.e. the comm of the thread group leader, like /proc/<pid>/comm.
+#[inline(always)]
+fn match_leader_comm(task: *const task_struct, tgid: u32) -> Option<u8> {
+    let leader: *const task_struct = unsafe { read_field(task, &TASK_GROUP_LEADER_OFFSET) }.ok()?;
+    if leader.is_null() {
+        return None;
+    }
+    let comm = unsafe { read_field::<Comm, _>(leader, &TASK_COMM_OFFSET) }.ok()?;
+    match_comm(tgid, &comm)
+}
+
+/// Adds `tgid` to `PID` if `comm` is in `COMM`, keeping the flags of already tracked processes.
+#[inline(always)]
+fn match_comm(tgid: u32, comm: &Comm) -> Option<u8> {
+    unsafe { COMM.get(comm) }?;
+    let _ = unsafe { PID.insert(&tgid, &COMM_MATCH, BPF_NOEXIST as u64) };
+    Some(COMM_MATCH)
+}
+
+/// Adds `tgid` to `PID` if the real uid of `task` is in `UID`, like the first `Uid` of
+/// /proc/<pid>/status.
+#[inline(always)]
+fn match_uid(task: *const task_struct, tgid: u32) -> Option<u8> {
+    if !matching(UID_MATCH) {
+        return None;
+    }
+    let cred: *const cred = unsafe { read_field(task, &TASK_REAL_CRED_OFFSET) }.ok()?;
+    if cred.is_null() {
+        return None;
+    }
+    let uid = unsafe { read_field::<u32, _>(cred, &CRED_UID_OFFSET) }.ok()?;
+    unsafe { UID.get(&uid) }?;
+    let _ = unsafe { PID.insert(&tgid, &UID_MATCH, BPF_NOEXIST as u64) };
+    Some(UID_MATCH)
+}
+
+/// Reads a `T` at the offset held by global `offset` from `base`, i.e. a field of the struct
+/// `base` points to, located at runtime.
+#[inline(always)]
+unsafe fn read_field<T, B>(base: *const B, offset: &'static u32) -> Result<T, i64> {
+    let field = base.cast::<u8>().wrapping_add(global(offset) as usize);
+    unsafe { bpf_probe_read_kernel(field.cast::<T>()) }
+}
+
+/// Loads a `T` at the offset held by global `offset` from `base` without
+/// `bpf_probe_read_kernel`, for pointers the verifier knows the type of (`tp_btf` arguments).
+/// The globals are read-only, so the verifier sees a constant offset and checks it against the
+/// kernel's BTF.
+#[inline(always)]
+unsafe fn load_field<T, B>(base: *const B, offset: &'static u32) -> T {
+    let field = base.cast::<u8>().wrapping_add(global(offset) as usize);
+    unsafe { field.cast::<T>().read() }
+}
+
+/// Reads the state of `task`, `__state` or the `long state` of kernels before 5.14.
+#[inline(always)]
+fn read_state(task: *const task_struct) -> Result<u64, i64> {
+    if global(&TASK_STATE_SIZE) == 8 {
+        unsafe { read_field::<u64, _>(task, &TASK_STATE_OFFSET) }
+    } else {
+        unsafe { read_field::<u32, _>(task, &TASK_STATE_OFFSET).map(u64::from) }
+    }
+}
+
+/// Like [`read_state`], for pointers [`load_field`] can read from.
+#[inline(always)]
+fn load_state(task: *const task_struct) -> u64 {
+    if global(&TASK_STATE_SIZE) == 8 {
+        unsafe { load_field::<u64, _>(task, &TASK_STATE_OFFSET) }
+    } else {
+        unsafe { load_field::<u32, _>(task, &TASK_STATE_OFFSET) as u64 }
+    }
+}
+
+/// Reads a global set by userspace. Volatile, so the compiler does not fold in its default.
+#[inline(always)]
+fn global(value: &'static u32) -> u32 {
+    unsafe { core::ptr::read_volatile(value) }
+}
 
 #[inline(always)]
 fn save_start_ts(pid: u32) {
@@ -180,6 +628,19 @@ fn save_start_ts(pid: u32) {
     let _ = unsafe { START.insert(&pid, &ts, 0) };
 }
 
+#[inline(always)]
+fn increment_slot(hist_map: &HashMap<u32, Histogram>, key: u32, slot: usize) {
+    if let Some(hist) = hist_map.get_ptr_mut(&key) {
+        unsafe {
+            (*hist)[slot] = (*hist)[slot].saturating_add(1);
+        }
+    } else {
+        let mut hist = [0; MAX_SLOTS];
+        hist[slot] = 1;
+        let _ = hist_map.insert(&key, &hist, 0);
+    }
+}
+
 #[inline(always)]
 fn log2_u64(v: u64) -> u32 {
     let hi: u32 = (v >> 32) as u32;
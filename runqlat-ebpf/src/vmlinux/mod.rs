@@ -0,0 +1,43 @@
+//! Kernel types read by the programs.
+//!
+//! Bindings differ between architectures, so they are kept per architecture in `<arch>.rs`,
+//! generated with aya-tool from the BTF of a kernel of that architecture:
+//!
+//! ```shell
+//! aya-tool generate task_struct cred > runqlat-ebpf/src/vmlinux/$(uname -m).rs
+//! ```
+//!
+//! The build script includes the file of the architecture userspace is built for, or bindings
+//! generated from the BTF file at `RUNQLAT_VMLINUX_BTF` if set, e.g. to build for a specific
+//! kernel:
+//!
+//! ```shell
+//! RUNQLAT_VMLINUX_BTF=/path/to/vmlinux.btf cargo build --release
+//! ```
+//!
+//! Building for an architecture without bindings fails unless `RUNQLAT_VMLINUX_BTF` is set.
+
+include!(env!("VMLINUX_BINDINGS"));
+
+/// Offsets of the fields read by the programs in the bindings, the defaults of the offset
+/// globals.
+pub mod offsets {
+    use core::mem::offset_of;
+
+    use super::{cred, task_struct};
+
+    pub const TASK_PID: u32 = offset_of!(task_struct, pid) as u32;
+    pub const TASK_TGID: u32 = offset_of!(task_struct, tgid) as u32;
+    #[cfg(not(vmlinux_legacy_state))]
+    pub const TASK_STATE: u32 = offset_of!(task_struct, __state) as u32;
+    #[cfg(not(vmlinux_legacy_state))]
+    pub const TASK_STATE_SIZE: u32 = size_of::<u32>() as u32;
+    #[cfg(vmlinux_legacy_state)]
+    pub const TASK_STATE: u32 = offset_of!(task_struct, state) as u32;
+    #[cfg(vmlinux_legacy_state)]
+    pub const TASK_STATE_SIZE: u32 = size_of::<i64>() as u32;
+    pub const TASK_COMM: u32 = offset_of!(task_struct, comm) as u32;
+    pub const TASK_GROUP_LEADER: u32 = offset_of!(task_struct, group_leader) as u32;
+    pub const TASK_REAL_CRED: u32 = offset_of!(task_struct, real_cred) as u32;
+    pub const CRED_UID: u32 = offset_of!(cred, uid) as u32;
+}
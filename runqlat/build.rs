@@ -1,6 +1,17 @@
-use anyhow::{Context as _, anyhow};
+use std::{env, fs, path::PathBuf};
+
+use anyhow::{Context as _, anyhow, bail};
 use aya_build::{Toolchain, cargo_metadata};
 
+#[allow(dead_code)]
+#[path = "src/btf.rs"]
+mod btf;
+
+/// Kernels to build eBPF objects for, as comma separated `RELEASE=BTF` pairs of the release
+/// (`uname -r`) of a kernel and the path of its BTF, see src/objects.rs. Without it, one object
+/// is built with the committed bindings.
+const KERNELS_ENV: &str = "RUNQLAT_KERNELS";
+
 fn main() -> anyhow::Result<()> {
     let cargo_metadata::Metadata { packages, .. } = cargo_metadata::MetadataCommand::new()
         .no_deps()
@@ -10,5 +21,97 @@ fn main() -> anyhow::Result<()> {
         .into_iter()
         .find(|cargo_metadata::Package { name, .. }| name.as_str() == "runqlat-ebpf")
         .ok_or_else(|| anyhow!("runqlat-ebpf package not found"))?;
-    aya_build::build_ebpf([ebpf_package], Toolchain::default())
+
+    println!("cargo:rerun-if-env-changed={KERNELS_ENV}");
+    let kernels = match env::var(KERNELS_ENV) {
+        Ok(kernels) => parse_kernels(&kernels)?,
+        Err(_) => Vec::new(),
+    };
+
+    // The eBPF programs need kernel type bindings for the target architecture, see
+    // runqlat-ebpf/src/vmlinux/mod.rs; fail before building them.
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").context("CARGO_CFG_TARGET_ARCH not set")?;
+    let bindings = ebpf_package
+        .manifest_path
+        .with_file_name("src")
+        .join("vmlinux")
+        .join(format!("{arch}.rs"));
+    // or bindings generated from this BTF, see runqlat-ebpf/build.rs
+    println!("cargo:rerun-if-env-changed=RUNQLAT_VMLINUX_BTF");
+    if !bindings.exists() && env::var_os("RUNQLAT_VMLINUX_BTF").is_none() && kernels.is_empty() {
+        bail!(
+            "no kernel type bindings at runqlat-ebpf/src/vmlinux/{arch}.rs, generate them on a \
+             machine of that architecture or set RUNQLAT_VMLINUX_BTF or {KERNELS_ENV}"
+        );
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").context("OUT_DIR not set")?);
+    let mut objects = String::from("static OBJECTS: &[Object] = &[\n");
+    if kernels.is_empty() {
+        aya_build::build_ebpf([ebpf_package], Toolchain::default())?;
+        let legacy_state = match env::var("RUNQLAT_VMLINUX_BTF") {
+            Ok(btf) => {
+                legacy_state(&fs::read(&btf).with_context(|| format!("failed to read {btf}"))?)
+                    .with_context(|| format!("invalid BTF in {btf}"))?
+            }
+            Err(_) => false,
+        };
+        objects.push_str(&object(None, None, legacy_state, &out_dir.join("runqlat")));
+    } else {
+        for (i, (release, btf)) in kernels.iter().enumerate() {
+            println!("cargo:rerun-if-changed={btf}");
+            let data = fs::read(btf).with_context(|| format!("failed to read {btf}"))?;
+
+            // SAFETY: build scripts are single threaded.
+            unsafe { env::set_var("RUNQLAT_VMLINUX_BTF", btf) };
+            aya_build::build_ebpf([ebpf_package.clone()], Toolchain::default())
+                .with_context(|| format!("failed to build eBPF object for {release}"))?;
+            let path = out_dir.join(format!("runqlat-{i}"));
+            fs::rename(out_dir.join("runqlat"), &path)?;
+            let legacy_state =
+                legacy_state(&data).with_context(|| format!("invalid BTF in {btf}"))?;
+            objects.push_str(&object(
+                Some(release),
+                Some(btf::checksum(&data)),
+                legacy_state,
+                &path,
+            ));
+        }
+    }
+    objects.push_str("];\n");
+    fs::write(out_dir.join("objects.rs"), objects).context("failed to write objects.rs")
+}
+
+fn parse_kernels(kernels: &str) -> anyhow::Result<Vec<(String, String)>> {
+    kernels
+        .split(',')
+        .filter(|kernel| !kernel.is_empty())
+        .map(|kernel| match kernel.split_once('=') {
+            Some((release, btf)) => Ok((release.to_owned(), btf.to_owned())),
+            None => bail!("invalid kernel {kernel:?} in {KERNELS_ENV}, expected RELEASE=BTF"),
+        })
+        .collect()
+}
+
+/// Whether the kernel of BTF `data` is older than 5.14, whose `task_struct` has `long state`
+/// instead of `__state`, as decided by runqlat-ebpf/build.rs for the bindings generated from it.
+/// The committed bindings are of kernels with `__state`.
+fn legacy_state(data: &[u8]) -> anyhow::Result<bool> {
+    Ok(btf::Btf::parse(data)?
+        .member("task_struct", "__state")
+        .is_err())
+}
+
+/// Entry of `OBJECTS` in src/objects.rs.
+fn object(
+    release: Option<&str>,
+    btf_checksum: Option<u64>,
+    legacy_state: bool,
+    path: &std::path::Path,
+) -> String {
+    let task_state = if legacy_state { "LegacyState" } else { "State" };
+    format!(
+        "    Object {{ release: {release:?}, btf_checksum: {btf_checksum:?}, task_state: \
+         TaskStateField::{task_state}, data: aya::include_bytes_aligned!({path:?}) }},\n"
+    )
 }
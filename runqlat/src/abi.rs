@@ -0,0 +1,145 @@
+use std::ptr;
+
+use anyhow::{Context as _, bail};
+use object::{Object as _, ObjectSection as _, ObjectSymbol as _, SymbolKind};
+use runqlat_common::{Abi, Comm, Histogram};
+
+use crate::{AttachMode, TaskStateField};
+
+/// Maps userspace reads or writes, with the sizes of their keys and values.
+const MAPS: [(&str, usize, usize); 7] = [
+    ("PID", size_of::<u32>(), size_of::<u8>()),
+    ("COMM", size_of::<Comm>(), size_of::<u8>()),
+    ("MATCH", size_of::<u32>(), size_of::<u8>()),
+    ("UID", size_of::<u32>(), size_of::<u8>()),
+    ("TGID", size_of::<u32>(), size_of::<u32>()),
+    ("HIST", size_of::<u32>(), size_of::<Histogram>()),
+    ("THREAD_HIST", size_of::<u32>(), size_of::<Histogram>()),
+];
+
+/// Section of the maps of aya-ebpf, each a [`MapDef`] named after the map.
+const MAPS_SECTION: &str = "maps";
+
+/// Global of the eBPF object holding the [`Abi`] it was built with.
+const ABI_SYMBOL: &str = "RUNQLAT_ABI";
+
+/// Leading fields of `bpf_map_def` in aya-ebpf.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MapDef {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+/// Checks that the eBPF object `data` was built with the same [`Abi`], has the maps userspace
+/// uses, with keys and values of the expected sizes, and the programs of `mode`. Returns the
+/// state field read at the offsets of the bindings it was built with.
+///
+/// Only objects read at runtime are checked: embedded ones are built with this version of
+/// runqlat.
+pub(crate) fn validate(data: &[u8], mode: AttachMode) -> anyhow::Result<TaskStateField> {
+    let file = object::File::parse(data).context("failed to parse eBPF object")?;
+
+    let abi: Abi = read_global(&file, ABI_SYMBOL).with_context(|| {
+        format!("eBPF object has no {ABI_SYMBOL} global, it was built by an older runqlat")
+    })?;
+    if abi != Abi::CURRENT {
+        bail!(
+            "eBPF object was built with {}, runqlat expects {}",
+            describe(&abi),
+            describe(&Abi::CURRENT)
+        );
+    }
+    // see `read_state` in runqlat-ebpf
+    let task_state = match read_global::<u32>(&file, "TASK_STATE_SIZE")? {
+        8 => TaskStateField::LegacyState,
+        _ => TaskStateField::State,
+    };
+
+    let mut errors = Vec::new();
+    for (name, key_size, value_size) in MAPS {
+        let Some(symbol) = symbol(&file, name).filter(|symbol| {
+            symbol
+                .section_index()
+                .and_then(|index| file.section_by_index(index).ok())
+                .is_some_and(|section| section.name() == Ok(MAPS_SECTION))
+        }) else {
+            errors.push(format!("map {name} is missing"));
+            continue;
+        };
+        let map: MapDef = read_symbol(&file, &symbol)?;
+        if map.key_size as usize != key_size {
+            errors.push(format!(
+                "keys of map {name} have {} bytes instead of {key_size}",
+                map.key_size
+            ));
+        }
+        if map.value_size as usize != value_size {
+            errors.push(format!(
+                "values of map {name} have {} bytes instead of {value_size}",
+                map.value_size
+            ));
+        }
+    }
+    for name in mode.programs() {
+        // programs are functions in their own section, named after their type
+        let is_program = symbol(&file, name).is_some_and(|symbol| {
+            symbol.kind() == SymbolKind::Text
+                && symbol
+                    .section_index()
+                    .and_then(|index| file.section_by_index(index).ok())
+                    .is_some_and(|section| section.name() != Ok(".text"))
+        });
+        if !is_program {
+            errors.push(format!("program {name} is missing"));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("{}", errors.join(", "));
+    }
+    Ok(task_state)
+}
+
+fn describe(abi: &Abi) -> String {
+    format!(
+        "ABI version {} with {} histogram slots ({} bytes) and {} byte comms",
+        abi.version, abi.max_slots, abi.histogram_size, abi.comm_size
+    )
+}
+
+fn symbol<'data, 'file>(
+    file: &'file object::File<'data>,
+    name: &str,
+) -> Option<object::Symbol<'data, 'file>> {
+    file.symbols().find(|symbol| symbol.name() == Ok(name))
+}
+
+/// Initial value of global `name` of the eBPF object `file`.
+///
+/// `T` must only have integer fields.
+fn read_global<T: Copy>(file: &object::File, name: &str) -> anyhow::Result<T> {
+    let symbol = symbol(file, name).with_context(|| format!("{name} not found"))?;
+    read_symbol(file, &symbol)
+}
+
+/// Initial value of the global at `symbol`, see [`read_global`].
+fn read_symbol<T: Copy>(file: &object::File, symbol: &object::Symbol) -> anyhow::Result<T> {
+    let name = symbol.name().unwrap_or_default();
+    let section = symbol
+        .section_index()
+        .and_then(|index| file.section_by_index(index).ok())
+        .with_context(|| format!("{name} is not defined in a section"))?;
+    let section = section
+        .data()
+        .with_context(|| format!("failed to read the section of {name}"))?;
+    // The address of a symbol of a relocatable object is its offset in its section.
+    let start = symbol.address() as usize;
+    let bytes = section
+        .get(start..start + size_of::<T>())
+        .with_context(|| format!("{name} is truncated"))?;
+    // SAFETY: `bytes` is as long as `T`, which only has integer fields.
+    Ok(unsafe { ptr::read_unaligned(bytes.as_ptr().cast::<T>()) })
+}
@@ -0,0 +1,600 @@
+use std::{fmt, fs, io, str::FromStr};
+
+use anyhow::{Context as _, anyhow, bail};
+use aya::{
+    Ebpf, EbpfLoader,
+    programs::{BtfTracePoint, FEntry, KProbe, RawTracePoint, TracePoint},
+};
+
+use crate::btf::Btf;
+
+/// Mount points of tracefs, tried in order.
+const TRACEFS_ROOTS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// How the eBPF programs hook into the scheduler.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AttachMode {
+    /// Raw tracepoints reading `task_struct` fields at offsets resolved from the running
+    /// kernel's BTF, or at those of the committed bindings if it is not available.
+    #[default]
+    RawTracepoint,
+    /// BTF-typed tracepoints (`tp_btf`), which read `task_struct` fields directly rather than
+    /// with `bpf_probe_read_kernel`. Needs the kernel's BTF and Linux 5.5 or later.
+    BtfTracepoint,
+    /// Regular `sched` tracepoints, whose fields are located from their format in tracefs, so
+    /// one binary works across kernel versions.
+    ///
+    /// The events only carry thread ids; threads are mapped to their process from procfs when
+    /// tracked and whenever they are switched out, so a thread's first wakeup after it started
+    /// may be missed. Processes are not matched in the kernel, see [`Profiler::track_comms`].
+    ///
+    /// [`Profiler::track_comms`]: crate::Profiler::track_comms
+    Tracepoint,
+    /// fentry programs on the scheduler functions `ttwu_do_wakeup` (or `ttwu_do_activate` where
+    /// it is inlined), `wake_up_new_task` and `finish_task_switch`, for kernels restricting
+    /// tracepoints. Needs the kernel's BTF.
+    ///
+    /// Exec is not hooked, so processes are matched by name or user when they first run after
+    /// exec rather than at exec.
+    Fentry,
+    /// kprobes on the same functions as [`AttachMode::Fentry`], which also work without BTF.
+    /// Attaching fails if a function is only in `/proc/kallsyms` under a name the compiler gave
+    /// it, e.g. `finish_task_switch.isra.0`.
+    Kprobe,
+}
+
+impl fmt::Display for AttachMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AttachMode::RawTracepoint => "raw_tracepoint",
+            AttachMode::BtfTracepoint => "tp_btf",
+            AttachMode::Tracepoint => "tracepoint",
+            AttachMode::Fentry => "fentry",
+            AttachMode::Kprobe => "kprobe",
+        })
+    }
+}
+
+impl FromStr for AttachMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        AttachMode::PRIORITY
+            .into_iter()
+            .find(|mode| mode.to_string() == s)
+            .ok_or_else(|| {
+                let modes: Vec<_> = AttachMode::PRIORITY.iter().map(|m| m.to_string()).collect();
+                anyhow!(
+                    "unknown attach mode {s}, expected one of {}",
+                    modes.join(", ")
+                )
+            })
+    }
+}
+
+impl AttachMode {
+    /// Modes tried by [`Profiler::try_new`], cheapest first.
+    ///
+    /// [`Profiler::try_new`]: crate::Profiler::try_new
+    pub const PRIORITY: [AttachMode; 5] = [
+        AttachMode::BtfTracepoint,
+        AttachMode::RawTracepoint,
+        AttachMode::Fentry,
+        AttachMode::Kprobe,
+        AttachMode::Tracepoint,
+    ];
+
+    /// Programs this mode may attach.
+    pub(crate) fn programs(&self) -> &'static [&'static str] {
+        match self {
+            AttachMode::RawTracepoint => &[
+                "sched_wakeup",
+                "sched_wakeup_new",
+                "sched_switch",
+                "sched_process_exec",
+                "sched_process_free",
+            ],
+            AttachMode::BtfTracepoint => &[
+                "tp_btf_sched_wakeup",
+                "tp_btf_sched_wakeup_new",
+                "tp_btf_sched_switch",
+                "sched_process_exec",
+                "sched_process_free",
+            ],
+            AttachMode::Tracepoint => {
+                &["tp_sched_wakeup", "tp_sched_wakeup_new", "tp_sched_switch"]
+            }
+            AttachMode::Fentry => &[
+                "fentry_ttwu_do_wakeup",
+                "fentry_ttwu_do_activate",
+                "fentry_wake_up_new_task",
+                "fentry_finish_task_switch",
+                "sched_process_free",
+            ],
+            AttachMode::Kprobe => &[
+                "kprobe_ttwu_do_wakeup",
+                "kprobe_ttwu_do_activate",
+                "kprobe_wake_up_new_task",
+                "kprobe_finish_task_switch",
+                "sched_process_free",
+            ],
+        }
+    }
+
+    /// Whether the programs of this mode read `task_struct`, at [`TaskOffsets`].
+    pub(crate) fn reads_task(&self) -> bool {
+        *self != AttachMode::Tracepoint
+    }
+
+    /// Whether the programs of this mode need offsets resolved from the kernel's BTF rather than
+    /// those of the committed bindings.
+    pub(crate) fn needs_btf(&self) -> bool {
+        matches!(self, AttachMode::BtfTracepoint | AttachMode::Fentry)
+    }
+
+    /// Sets the global variables the programs of this mode read.
+    pub(crate) fn configure<'a>(
+        &self,
+        loader: &mut EbpfLoader<'a>,
+        offsets: &'a TracepointOffsets,
+    ) {
+        if *self == AttachMode::Tracepoint {
+            loader
+                .set_global("WAKEUP_PID_OFFSET", &offsets.wakeup_pid, true)
+                .set_global("SWITCH_PREV_PID_OFFSET", &offsets.prev_pid, true)
+                .set_global("SWITCH_PREV_STATE_OFFSET", &offsets.prev_state, true)
+                .set_global("SWITCH_PREV_STATE_SIZE", &offsets.prev_state_size, true)
+                .set_global("SWITCH_NEXT_PID_OFFSET", &offsets.next_pid, true);
+        }
+    }
+
+    /// Loads and attaches the programs of this mode.
+    pub(crate) fn attach(&self, ebpf: &mut Ebpf) -> anyhow::Result<()> {
+        match self {
+            AttachMode::RawTracepoint => {
+                for tp in [
+                    "sched_wakeup",
+                    "sched_wakeup_new",
+                    "sched_switch",
+                    "sched_process_exec",
+                ] {
+                    attach_raw_tracepoint(ebpf, tp)?;
+                }
+                attach_process_free(ebpf)?;
+            }
+            AttachMode::BtfTracepoint => {
+                let btf = aya::Btf::from_sys_fs().context("failed to load the kernel's BTF")?;
+                for (name, tp) in [
+                    ("tp_btf_sched_wakeup", "sched_wakeup"),
+                    ("tp_btf_sched_wakeup_new", "sched_wakeup_new"),
+                    ("tp_btf_sched_switch", "sched_switch"),
+                ] {
+                    let prog: &mut BtfTracePoint = ebpf.program_mut(name).unwrap().try_into()?;
+                    prog.load(tp, &btf)
+                        .with_context(|| format!("failed to load {name}"))?;
+                    prog.attach()
+                        .with_context(|| format!("failed to attach {name}"))?;
+                }
+                // exec is not a hot path
+                attach_raw_tracepoint(ebpf, "sched_process_exec")?;
+                attach_process_free(ebpf)?;
+            }
+            AttachMode::Fentry => {
+                let btf = aya::Btf::from_sys_fs().context("failed to load the kernel's BTF")?;
+                let mut attach = |function: &str| -> anyhow::Result<()> {
+                    let name = format!("fentry_{function}");
+                    let prog: &mut FEntry = ebpf.program_mut(&name).unwrap().try_into()?;
+                    prog.load(function, &btf)
+                        .with_context(|| format!("failed to load {name}"))?;
+                    prog.attach()
+                        .with_context(|| format!("failed to attach {name}"))?;
+                    Ok(())
+                };
+                first_of(WAKEUP_FUNCTIONS, &mut attach)?;
+                SCHED_FUNCTIONS.into_iter().try_for_each(attach)?;
+                attach_process_free(ebpf)?;
+            }
+            AttachMode::Kprobe => {
+                let symbols = fs::read_to_string(KALLSYMS)
+                    .with_context(|| format!("failed to read {KALLSYMS}"))?;
+                let mut attach = |function: &str| -> anyhow::Result<()> {
+                    let symbol = kernel_symbol(&symbols, function)?;
+                    let name = format!("kprobe_{function}");
+                    let prog: &mut KProbe = ebpf.program_mut(&name).unwrap().try_into()?;
+                    prog.load()?;
+                    prog.attach(symbol, 0)
+                        .with_context(|| format!("failed to attach {name} to {symbol}"))?;
+                    Ok(())
+                };
+                first_of(WAKEUP_FUNCTIONS, &mut attach)?;
+                SCHED_FUNCTIONS.into_iter().try_for_each(attach)?;
+                attach_process_free(ebpf)?;
+            }
+            AttachMode::Tracepoint => {
+                for (name, tp) in [
+                    ("tp_sched_wakeup", "sched_wakeup"),
+                    ("tp_sched_wakeup_new", "sched_wakeup_new"),
+                    ("tp_sched_switch", "sched_switch"),
+                ] {
+                    let prog: &mut TracePoint = ebpf.program_mut(name).unwrap().try_into()?;
+                    prog.load()?;
+                    prog.attach("sched", tp)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Functions enqueueing woken up tasks hooked in [`AttachMode::Fentry`] and
+/// [`AttachMode::Kprobe`], tried in order: `ttwu_do_wakeup` is inlined on some kernels.
+const WAKEUP_FUNCTIONS: [&str; 2] = ["ttwu_do_wakeup", "ttwu_do_activate"];
+
+/// Other scheduler functions hooked in [`AttachMode::Fentry`] and [`AttachMode::Kprobe`].
+const SCHED_FUNCTIONS: [&str; 2] = ["wake_up_new_task", "finish_task_switch"];
+
+/// Attaches to the first function of `functions` `attach` succeeds with.
+fn first_of(
+    functions: impl IntoIterator<Item = &'static str>,
+    mut attach: impl FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut tried = Vec::new();
+    let mut errors = Vec::new();
+    for function in functions {
+        match attach(function) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("{e:#}")),
+        }
+        tried.push(function);
+    }
+    bail!(
+        "failed to attach to any of {}: {}",
+        tried.join(", "),
+        errors.join("; ")
+    )
+}
+
+const KALLSYMS: &str = "/proc/kallsyms";
+
+/// Symbol of kernel function `function` in `kallsyms`. Only the function itself is attached to:
+/// its renamed variants, e.g. `finish_task_switch.isra.0` or `.part.0`, may be called with other
+/// arguments or be only part of it.
+fn kernel_symbol<'a>(kallsyms: &'a str, function: &str) -> anyhow::Result<&'a str> {
+    let mut renamed = Vec::new();
+    for line in kallsyms.lines() {
+        // e.g. `ffffffff810d1230 t finish_task_switch`
+        let Some(symbol) = line.split_whitespace().nth(2) else {
+            continue;
+        };
+        if symbol == function {
+            return Ok(symbol);
+        }
+        if symbol
+            .strip_prefix(function)
+            .is_some_and(|suffix| suffix.starts_with('.'))
+        {
+            renamed.push(symbol);
+        }
+    }
+    if renamed.is_empty() {
+        bail!("{function} is not in {KALLSYMS}, it may be inlined");
+    }
+    bail!(
+        "{function} is not in {KALLSYMS}, only its variants {}",
+        renamed.join(", ")
+    )
+}
+
+fn attach_raw_tracepoint(ebpf: &mut Ebpf, tp: &str) -> anyhow::Result<()> {
+    let prog: &mut RawTracePoint = ebpf.program_mut(tp).unwrap().try_into()?;
+    prog.load()?;
+    prog.attach(tp)?;
+    Ok(())
+}
+
+/// Attaches the program untracking processes matched by the eBPF program once they are reaped.
+fn attach_process_free(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    let prog: &mut TracePoint = ebpf.program_mut("sched_process_free").unwrap().try_into()?;
+    prog.load()?;
+    prog.attach("sched", "sched_process_free")?;
+    Ok(())
+}
+
+/// Offsets of the `sched` tracepoint fields read in [`AttachMode::Tracepoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TracepointOffsets {
+    /// `pid` of `sched_wakeup` and `sched_wakeup_new`, which share their format.
+    wakeup_pid: u32,
+    prev_pid: u32,
+    prev_state: u32,
+    /// Size of `prev_state` in bytes, a `long` on most kernels.
+    prev_state_size: u32,
+    next_pid: u32,
+}
+
+impl Default for TracepointOffsets {
+    /// Layout of the events since Linux 4.x on 64-bit architectures.
+    fn default() -> Self {
+        Self {
+            wakeup_pid: 24,
+            prev_pid: 24,
+            prev_state: 32,
+            prev_state_size: 8,
+            next_pid: 56,
+        }
+    }
+}
+
+impl TracepointOffsets {
+    /// Reads the offsets from the event formats in tracefs.
+    pub(crate) fn read() -> anyhow::Result<Self> {
+        Self::parse(
+            &event_format("sched_wakeup")?,
+            &event_format("sched_switch")?,
+        )
+    }
+
+    /// Parses the offsets from the formats of `sched_wakeup` and `sched_switch`.
+    fn parse(wakeup: &str, switch: &str) -> anyhow::Result<Self> {
+        let field = |format: &str, event: &str, name: &str| {
+            field(format, name).ok_or_else(|| anyhow!("no field {name} in format of {event}"))
+        };
+
+        let (prev_state, prev_state_size) = field(switch, "sched_switch", "prev_state")?;
+        Ok(Self {
+            wakeup_pid: field(wakeup, "sched_wakeup", "pid")?.0,
+            prev_pid: field(switch, "sched_switch", "prev_pid")?.0,
+            prev_state,
+            prev_state_size,
+            next_pid: field(switch, "sched_switch", "next_pid")?.0,
+        })
+    }
+}
+
+/// Variant of the `task_struct` field holding the task state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStateField {
+    /// `unsigned int __state`, since Linux 5.14.
+    State,
+    /// `long state`, before Linux 5.14.
+    LegacyState,
+}
+
+impl TaskStateField {
+    /// Name of the field in `task_struct`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskStateField::State => "__state",
+            TaskStateField::LegacyState => "state",
+        }
+    }
+}
+
+/// How the loaded programs read the scheduler events, see [`Profiler::diagnostics`].
+///
+/// [`Profiler::diagnostics`]: crate::Profiler::diagnostics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub mode: AttachMode,
+    /// Whether `task_struct` offsets were resolved from the kernel's BTF rather than taken from
+    /// the committed bindings. Always `false` in [`AttachMode::Tracepoint`].
+    pub btf_offsets: bool,
+    /// Release of the kernel the loaded embedded eBPF object was built for, `None` for the object
+    /// built with the committed bindings or one loaded from a file.
+    pub object_release: Option<&'static str>,
+    /// State field read from `task_struct`, `None` in [`AttachMode::Tracepoint`].
+    pub task_state: Option<TaskStateField>,
+}
+
+/// Offsets of the kernel struct fields read by the modes other than [`AttachMode::Tracepoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TaskOffsets {
+    pid: u32,
+    tgid: u32,
+    state: u32,
+    /// Size of the state field in bytes.
+    state_size: u32,
+    pub(crate) state_field: TaskStateField,
+    comm: u32,
+    group_leader: u32,
+    real_cred: u32,
+    /// `uid` of `struct cred`.
+    cred_uid: u32,
+    /// Position of `p` in the parameters of `ttwu_do_wakeup`, if it is in the BTF.
+    wakeup_task_arg: Option<u32>,
+}
+
+impl TaskOffsets {
+    /// Locates the fields in the kernel's BTF.
+    pub(crate) fn from_btf(btf: &Btf) -> anyhow::Result<Self> {
+        let task = |member| btf.member("task_struct", member);
+        let (state_field, state) = match task("__state") {
+            Ok(state) => (TaskStateField::State, state),
+            Err(e) => match task("state") {
+                Ok(state) => (TaskStateField::LegacyState, state),
+                Err(_) => return Err(e),
+            },
+        };
+        Ok(Self {
+            pid: task("pid")?.offset,
+            tgid: task("tgid")?.offset,
+            state: state.offset,
+            state_size: state.size,
+            state_field,
+            comm: task("comm")?.offset,
+            group_leader: task("group_leader")?.offset,
+            real_cred: task("real_cred")?.offset,
+            cred_uid: btf.member("cred", "uid")?.offset,
+            wakeup_task_arg: btf.param_index("ttwu_do_wakeup", "p").ok(),
+        })
+    }
+
+    /// Sets the global variables holding the offsets, replacing those of the committed bindings.
+    pub(crate) fn configure<'a>(&'a self, loader: &mut EbpfLoader<'a>) {
+        loader
+            .set_global("TASK_PID_OFFSET", &self.pid, true)
+            .set_global("TASK_TGID_OFFSET", &self.tgid, true)
+            .set_global("TASK_STATE_OFFSET", &self.state, true)
+            .set_global("TASK_STATE_SIZE", &self.state_size, true)
+            .set_global("TASK_COMM_OFFSET", &self.comm, true)
+            .set_global("TASK_GROUP_LEADER_OFFSET", &self.group_leader, true)
+            .set_global("TASK_REAL_CRED_OFFSET", &self.real_cred, true)
+            .set_global("CRED_UID_OFFSET", &self.cred_uid, true);
+        if let Some(wakeup_task_arg) = &self.wakeup_task_arg {
+            loader.set_global("WAKEUP_TASK_ARG", wakeup_task_arg, true);
+        }
+    }
+}
+
+/// Format of the `sched` event `event`.
+fn event_format(event: &str) -> anyhow::Result<String> {
+    let mut last_error = None;
+    for root in TRACEFS_ROOTS {
+        let path = format!("{root}/events/sched/{event}/format");
+        match fs::read_to_string(&path) {
+            Ok(format) => return Ok(format),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => last_error = Some(anyhow::Error::new(e).context(path)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("tracefs is not mounted")))
+        .with_context(|| format!("failed to read format of sched:{event}"))
+}
+
+/// Offset and size of field `name` in an event format, from lines like
+/// `field:pid_t pid; offset:24; size:4; signed:1;` (separated by tabs).
+fn field(format: &str, name: &str) -> Option<(u32, u32)> {
+    format.lines().find_map(|line| {
+        let mut parts = line.trim().split(';').map(str::trim);
+        let declaration = parts.next()?.strip_prefix("field:")?;
+        let field_name = declaration.rsplit(' ').next()?;
+        if field_name.split('[').next()? != name {
+            return None;
+        }
+
+        let mut offset = None;
+        let mut size = None;
+        for part in parts {
+            if let Some(value) = part.strip_prefix("offset:") {
+                offset = value.parse().ok();
+            } else if let Some(value) = part.strip_prefix("size:") {
+                size = value.parse().ok();
+            }
+        }
+        Some((offset?, size?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_symbol_exact() {
+        let kallsyms = "ffffffff810d1000 t finish_task_switch.isra.0\n\
+                        ffffffff810d1230 T finish_task_switch\n\
+                        ffffffff810d1460 T finish_task_switch_cleanup\n";
+        assert_eq!(
+            kernel_symbol(kallsyms, "finish_task_switch").unwrap(),
+            "finish_task_switch"
+        );
+    }
+
+    #[test]
+    fn kernel_symbol_renamed() {
+        let kallsyms = "ffffffff810d0f00 t finish_task_switch.cold\n\
+                        ffffffff810d1000 t finish_task_switch.part.0\n\
+                        ffffffff810d1230 t finish_task_switch.isra.0\n\
+                        ffffffff810d1460 T finish_task_switch_cleanup\n";
+        assert_eq!(
+            kernel_symbol(kallsyms, "finish_task_switch")
+                .unwrap_err()
+                .to_string(),
+            "finish_task_switch is not in /proc/kallsyms, only its variants \
+             finish_task_switch.cold, finish_task_switch.part.0, finish_task_switch.isra.0"
+        );
+
+        let kallsyms = "ffffffff810d1230 t ttwu_do_activate.constprop.0.isra.0 [kernel]\n\
+                        ffffffff810d1460 T ttwu_do_wakeup_stats\n";
+        assert_eq!(
+            kernel_symbol(kallsyms, "ttwu_do_wakeup")
+                .unwrap_err()
+                .to_string(),
+            "ttwu_do_wakeup is not in /proc/kallsyms, it may be inlined"
+        );
+        assert!(kernel_symbol(kallsyms, "ttwu_do_activate").is_err());
+    }
+
+    /// Format of `sched:sched_wakeup` on Linux 6.8 x86_64.
+    const SCHED_WAKEUP: &str = "\
+name: sched_wakeup
+ID: 318
+format:
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+\tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+\tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+\tfield:char comm[16];\toffset:8;\tsize:16;\tsigned:0;
+\tfield:pid_t pid;\toffset:24;\tsize:4;\tsigned:1;
+\tfield:int prio;\toffset:28;\tsize:4;\tsigned:1;
+\tfield:int target_cpu;\toffset:32;\tsize:4;\tsigned:1;
+
+print fmt: \"comm=%s pid=%d prio=%d target_cpu=%03d\", REC->comm, REC->pid, REC->prio, REC->target_cpu
+";
+
+    /// Format of `sched:sched_switch` on Linux 6.8 x86_64.
+    const SCHED_SWITCH: &str = "\
+name: sched_switch
+ID: 316
+format:
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+\tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+\tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+\tfield:char prev_comm[16];\toffset:8;\tsize:16;\tsigned:0;
+\tfield:pid_t prev_pid;\toffset:24;\tsize:4;\tsigned:1;
+\tfield:int prev_prio;\toffset:28;\tsize:4;\tsigned:1;
+\tfield:long prev_state;\toffset:32;\tsize:8;\tsigned:1;
+\tfield:char next_comm[16];\toffset:40;\tsize:16;\tsigned:0;
+\tfield:pid_t next_pid;\toffset:56;\tsize:4;\tsigned:1;
+\tfield:int next_prio;\toffset:60;\tsize:4;\tsigned:1;
+
+print fmt: \"prev_comm=%s prev_pid=%d prev_prio=%d prev_state=%s%s ==> next_comm=%s next_pid=%d next_prio=%d\", REC->prev_comm, REC->prev_pid, REC->prev_prio, (REC->prev_state & ((((0x00000000 | 0x00000001 | 0x00000002 | 0x00000004 | 0x00000008 | 0x00000010 | 0x00000020 | 0x00000040) + 1) << 1) - 1)) ? \"R+\" : \"R\", REC->next_comm, REC->next_pid, REC->next_prio
+";
+
+    #[test]
+    fn tracepoint_offsets() {
+        assert_eq!(
+            TracepointOffsets::parse(SCHED_WAKEUP, SCHED_SWITCH).unwrap(),
+            TracepointOffsets::default()
+        );
+    }
+
+    #[test]
+    fn format_field() {
+        assert_eq!(field(SCHED_WAKEUP, "pid"), Some((24, 4)));
+        assert_eq!(field(SCHED_WAKEUP, "comm"), Some((8, 16)));
+        assert_eq!(field(SCHED_WAKEUP, "common_pid"), Some((4, 4)));
+        assert_eq!(field(SCHED_SWITCH, "prev_state"), Some((32, 8)));
+        assert_eq!(field(SCHED_SWITCH, "next_pid"), Some((56, 4)));
+        // only whole names match
+        assert_eq!(field(SCHED_SWITCH, "pid"), None);
+        assert_eq!(field(SCHED_SWITCH, "state"), None);
+    }
+
+    #[test]
+    fn tracepoint_offsets_missing_field() {
+        let switch = SCHED_SWITCH.replace("next_pid;", "next_tid;");
+        let e = TracepointOffsets::parse(SCHED_WAKEUP, &switch).unwrap_err();
+        assert_eq!(e.to_string(), "no field next_pid in format of sched_switch");
+
+        // the formats were swapped
+        let e = TracepointOffsets::parse(SCHED_SWITCH, SCHED_WAKEUP).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "no field prev_state in format of sched_switch"
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context as _, anyhow, bail, ensure};
+
+/// BTF of the running kernel.
+pub(crate) const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+// Kinds of types, see linux/btf.h.
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC: u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+/// Maximum number of types followed to resolve a type, so that cycles in malformed BTF fail
+/// rather than hang.
+const MAX_DEPTH: usize = 32;
+
+/// FNV-1a hash of BTF data, identifying the kernel build it describes.
+pub(crate) fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Type of a BTF type graph. Only the parts needed to locate struct members are kept.
+#[derive(Debug, Clone)]
+struct Type {
+    kind: u32,
+    name_off: u32,
+    /// Size for structs, unions, ints and enums; referenced type id for modifiers, typedefs and
+    /// functions.
+    size_or_type: u32,
+    /// Members of structs and unions, parameters of function prototypes.
+    members: Vec<Member>,
+    /// Element type id and number of elements of arrays.
+    array: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Member {
+    name_off: u32,
+    type_id: u32,
+    /// Offset from the start of the struct in bits.
+    bit_offset: u32,
+}
+
+/// Member of a struct located by [`Btf::member`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Field {
+    /// Offset from the start of the struct in bytes.
+    pub(crate) offset: u32,
+    /// Size in bytes.
+    pub(crate) size: u32,
+}
+
+/// Minimal parser of the BTF type information of a kernel.
+#[derive(Debug, Clone)]
+pub(crate) struct Btf {
+    /// Types by id; id 0 is `void`.
+    types: Vec<Type>,
+    strings: Vec<u8>,
+}
+
+impl Btf {
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("invalid BTF in {}", path.display()))
+    }
+
+    pub(crate) fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let u16_at = |offset: usize| -> anyhow::Result<u16> {
+            let bytes = data
+                .get(offset..offset + 2)
+                .ok_or_else(|| anyhow!("truncated"))?;
+            Ok(u16::from_ne_bytes(bytes.try_into()?))
+        };
+        let u32_at = |offset: usize| -> anyhow::Result<u32> {
+            let bytes = data
+                .get(offset..offset + 4)
+                .ok_or_else(|| anyhow!("truncated"))?;
+            Ok(u32::from_ne_bytes(bytes.try_into()?))
+        };
+
+        // struct btf_header
+        ensure!(u16_at(0)? == BTF_MAGIC, "bad magic");
+        let hdr_len = u32_at(4)? as usize;
+        let type_off = hdr_len + u32_at(8)? as usize;
+        let type_len = u32_at(12)? as usize;
+        let str_off = hdr_len + u32_at(16)? as usize;
+        let str_len = u32_at(20)? as usize;
+        let strings = data
+            .get(str_off..str_off + str_len)
+            .ok_or_else(|| anyhow!("truncated string section"))?
+            .to_vec();
+
+        let mut types = vec![Type {
+            kind: 0,
+            name_off: 0,
+            size_or_type: 0,
+            members: Vec::new(),
+            array: None,
+        }];
+        let mut offset = type_off;
+        while offset < type_off + type_len {
+            // struct btf_type
+            let name_off = u32_at(offset)?;
+            let info = u32_at(offset + 4)?;
+            let size_or_type = u32_at(offset + 8)?;
+            offset += 12;
+
+            let kind = (info >> 24) & 0x1f;
+            let vlen = (info & 0xffff) as usize;
+            let kind_flag = info >> 31 == 1;
+            let mut members = Vec::new();
+            let mut array = None;
+            match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => offset += 4,
+                BTF_KIND_ARRAY => {
+                    // struct btf_array
+                    array = Some((u32_at(offset)?, u32_at(offset + 8)?));
+                    offset += 12;
+                }
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    for _ in 0..vlen {
+                        // struct btf_member; with kind_flag the offset also holds the
+                        // bitfield size in its top 8 bits.
+                        let bit_offset = u32_at(offset + 8)?;
+                        members.push(Member {
+                            name_off: u32_at(offset)?,
+                            type_id: u32_at(offset + 4)?,
+                            bit_offset: if kind_flag {
+                                bit_offset & 0xff_ffff
+                            } else {
+                                bit_offset
+                            },
+                        });
+                        offset += 12;
+                    }
+                }
+                BTF_KIND_FUNC_PROTO => {
+                    for _ in 0..vlen {
+                        // struct btf_param
+                        members.push(Member {
+                            name_off: u32_at(offset)?,
+                            type_id: u32_at(offset + 4)?,
+                            bit_offset: 0,
+                        });
+                        offset += 8;
+                    }
+                }
+                BTF_KIND_ENUM => offset += vlen * 8,
+                BTF_KIND_DATASEC | BTF_KIND_ENUM64 => offset += vlen * 12,
+                // Kinds without trailing data: PTR, FWD, modifiers, TYPEDEF, FUNC, FLOAT and
+                // TYPE_TAG.
+                kind if kind <= BTF_KIND_TYPE_TAG => {}
+                kind => bail!("unknown BTF kind {kind}"),
+            }
+            types.push(Type {
+                kind,
+                name_off,
+                size_or_type,
+                members,
+                array,
+            });
+        }
+
+        Ok(Self { types, strings })
+    }
+
+    fn name(&self, name_off: u32) -> &str {
+        let Some(name) = self.strings.get(name_off as usize..) else {
+            return "";
+        };
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        std::str::from_utf8(&name[..len]).unwrap_or_default()
+    }
+
+    /// Id of the struct named `name` with a definition.
+    fn struct_id(&self, name: &str) -> Option<u32> {
+        self.types
+            .iter()
+            .position(|ty| {
+                ty.kind == BTF_KIND_STRUCT && ty.size_or_type > 0 && self.name(ty.name_off) == name
+            })
+            .map(|id| id as u32)
+    }
+
+    /// Follows typedefs and modifiers to the underlying type.
+    fn resolve(&self, mut id: u32) -> anyhow::Result<&Type> {
+        for _ in 0..MAX_DEPTH {
+            let ty = self
+                .types
+                .get(id as usize)
+                .ok_or_else(|| anyhow!("type {id} not found"))?;
+            match ty.kind {
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+                | BTF_KIND_TYPE_TAG => id = ty.size_or_type,
+                _ => return Ok(ty),
+            }
+        }
+        bail!("type {id} is nested more than {MAX_DEPTH} times")
+    }
+
+    /// Size of type `id` in bytes, following at most `depth` nested arrays.
+    fn size(&self, id: u32, depth: usize) -> anyhow::Result<u32> {
+        let ty = self.resolve(id)?;
+        match ty.kind {
+            BTF_KIND_INT | BTF_KIND_ENUM | BTF_KIND_ENUM64 | BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                Ok(ty.size_or_type)
+            }
+            // Pointers are as wide as the kernel's longs.
+            BTF_KIND_PTR => Ok(size_of::<usize>() as u32),
+            BTF_KIND_ARRAY => {
+                let (elem_type, nelems) = ty.array.context("array without element type")?;
+                ensure!(depth > 0, "type {id} is nested more than {MAX_DEPTH} times");
+                self.size(elem_type, depth - 1)?
+                    .checked_mul(nelems)
+                    .ok_or_else(|| anyhow!("size of array type {id} overflows"))
+            }
+            kind => bail!("type {id} of kind {kind} has no size"),
+        }
+    }
+
+    /// Locates member `member` of struct `name`, looking into anonymous structs and unions,
+    /// e.g. those wrapping the randomized part of `task_struct`.
+    pub(crate) fn member(&self, name: &str, member: &str) -> anyhow::Result<Field> {
+        let id = self
+            .struct_id(name)
+            .ok_or_else(|| anyhow!("struct {name} not found"))?;
+        self.find_member(&self.types[id as usize], member, MAX_DEPTH)
+            .with_context(|| format!("invalid struct {name}"))?
+            .ok_or_else(|| anyhow!("struct {name} has no member {member}"))
+    }
+
+    /// Position of parameter `param` in the arguments of function `name`.
+    pub(crate) fn param_index(&self, name: &str, param: &str) -> anyhow::Result<u32> {
+        let func = self
+            .types
+            .iter()
+            .find(|ty| ty.kind == BTF_KIND_FUNC && self.name(ty.name_off) == name)
+            .ok_or_else(|| anyhow!("function {name} not found"))?;
+        let proto = self
+            .types
+            .get(func.size_or_type as usize)
+            .filter(|ty| ty.kind == BTF_KIND_FUNC_PROTO)
+            .ok_or_else(|| anyhow!("function {name} has no prototype"))?;
+        proto
+            .members
+            .iter()
+            .position(|p| self.name(p.name_off) == param)
+            .map(|i| i as u32)
+            .ok_or_else(|| anyhow!("function {name} has no parameter {param}"))
+    }
+
+    /// Member `member` of struct or union `ty`, looking into at most `depth` nested anonymous
+    /// structs and unions.
+    fn find_member(&self, ty: &Type, member: &str, depth: usize) -> anyhow::Result<Option<Field>> {
+        for m in &ty.members {
+            if m.name_off == 0 {
+                let inner = self.resolve(m.type_id)?;
+                if !matches!(inner.kind, BTF_KIND_STRUCT | BTF_KIND_UNION) {
+                    continue;
+                }
+                ensure!(
+                    depth > 0,
+                    "anonymous members are nested more than {MAX_DEPTH} times"
+                );
+                if let Some(field) = self.find_member(inner, member, depth - 1)? {
+                    return Ok(Some(Field {
+                        offset: m.bit_offset / 8 + field.offset,
+                        ..field
+                    }));
+                }
+                continue;
+            }
+            if self.name(m.name_off) == member {
+                return Ok(Some(Field {
+                    offset: m.bit_offset / 8,
+                    size: self
+                        .size(m.type_id, MAX_DEPTH)
+                        .with_context(|| format!("invalid type of member {member}"))?,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds BTF data from `btf_type` records, with names appended to the string section.
+    #[derive(Default)]
+    struct Builder {
+        types: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn name(&mut self, name: &str) -> u32 {
+            if self.strings.is_empty() {
+                self.strings.push(0);
+            }
+            if name.is_empty() {
+                return 0;
+            }
+            let name_off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            name_off
+        }
+
+        fn push(&mut self, words: &[u32]) {
+            for word in words {
+                self.types.extend_from_slice(&word.to_ne_bytes());
+            }
+        }
+
+        fn ty(&mut self, name: &str, kind: u32, vlen: u32, size_or_type: u32, extra: &[u32]) {
+            let name_off = self.name(name);
+            self.push(&[name_off, kind << 24 | vlen, size_or_type]);
+            self.push(extra);
+        }
+
+        fn members(&mut self, members: &[(&str, u32, u32)]) -> Vec<u32> {
+            members
+                .iter()
+                .flat_map(|&(name, type_id, bit_offset)| [self.name(name), type_id, bit_offset])
+                .collect()
+        }
+
+        fn build(mut self) -> Vec<u8> {
+            self.name("");
+            let mut data = Vec::new();
+            data.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+            data.extend_from_slice(&[1, 0]);
+            for word in [24, 0, self.types.len() as u32, self.types.len() as u32] {
+                data.extend_from_slice(&word.to_ne_bytes());
+            }
+            data.extend_from_slice(&(self.strings.len() as u32).to_ne_bytes());
+            data.extend(self.types);
+            data.extend(self.strings);
+            data
+        }
+    }
+
+    /// A `task_struct` with a member in an anonymous struct and a bitfield, and
+    /// `ttwu_do_wakeup(struct rq *rq, struct task_struct *p, int wake_flags)`.
+    fn kernel_btf() -> Vec<u8> {
+        let mut btf = Builder::default();
+        // 1: int
+        btf.ty("int", BTF_KIND_INT, 0, 4, &[32]);
+        // 2: char
+        btf.ty("char", BTF_KIND_INT, 0, 1, &[8]);
+        // 3: char[16]
+        btf.ty("", BTF_KIND_ARRAY, 0, 0, &[2, 1, 16]);
+        // 4: pid_t
+        btf.ty("pid_t", BTF_KIND_TYPEDEF, 0, 1, &[]);
+        // 5: struct { pid_t pid; pid_t tgid; }
+        let members = btf.members(&[("pid", 4, 0), ("tgid", 4, 32)]);
+        btf.ty("", BTF_KIND_STRUCT, 2, 8, &members);
+        // 6: struct task_struct, with kind_flag set for the bitfield
+        let members = btf.members(&[
+            ("__state", 1, 0),
+            ("in_iowait", 1, 1 << 24 | 32),
+            ("", 5, 64),
+            ("comm", 3, 128),
+        ]);
+        btf.ty("task_struct", BTF_KIND_STRUCT | 1 << 7, 4, 32, &members);
+        // 7: struct task_struct *
+        btf.ty("", BTF_KIND_PTR, 0, 6, &[]);
+        // 8: struct rq, declared only
+        btf.ty("rq", 7, 0, 0, &[]);
+        // 9: struct rq *
+        btf.ty("", BTF_KIND_PTR, 0, 8, &[]);
+        // 10: void (struct rq *, struct task_struct *, int)
+        let params: Vec<u32> = [("rq", 9), ("p", 7), ("wake_flags", 1)]
+            .into_iter()
+            .flat_map(|(name, type_id)| [btf.name(name), type_id])
+            .collect();
+        btf.ty("", BTF_KIND_FUNC_PROTO, 3, 0, &params);
+        // 11: ttwu_do_wakeup
+        btf.ty("ttwu_do_wakeup", BTF_KIND_FUNC, 0, 10, &[]);
+        btf.build()
+    }
+
+    #[test]
+    fn member() {
+        let btf = Btf::parse(&kernel_btf()).unwrap();
+        let field = |member| btf.member("task_struct", member).unwrap();
+        assert_eq!(field("__state"), Field { offset: 0, size: 4 });
+        assert_eq!(field("in_iowait"), Field { offset: 4, size: 4 });
+        assert_eq!(field("pid"), Field { offset: 8, size: 4 });
+        assert_eq!(
+            field("tgid"),
+            Field {
+                offset: 12,
+                size: 4
+            }
+        );
+        assert_eq!(
+            field("comm"),
+            Field {
+                offset: 16,
+                size: 16
+            }
+        );
+        assert!(btf.member("task_struct", "state").is_err());
+        assert!(btf.member("rq", "curr").is_err());
+    }
+
+    #[test]
+    fn param_index() {
+        let btf = Btf::parse(&kernel_btf()).unwrap();
+        assert_eq!(btf.param_index("ttwu_do_wakeup", "rq").unwrap(), 0);
+        assert_eq!(btf.param_index("ttwu_do_wakeup", "p").unwrap(), 1);
+        assert!(btf.param_index("ttwu_do_wakeup", "task").is_err());
+        assert!(btf.param_index("ttwu_do_activate", "p").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let data = kernel_btf();
+        assert!(Btf::parse(&data[..data.len() - 8]).is_err());
+        assert!(Btf::parse(&[0; 24]).is_err());
+        assert!(Btf::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn cycles() {
+        let mut btf = Builder::default();
+        // 1: typedef of 2, 2: const of 1
+        btf.ty("a", BTF_KIND_TYPEDEF, 0, 2, &[]);
+        btf.ty("", BTF_KIND_CONST, 0, 1, &[]);
+        // 3: array of itself
+        btf.ty("", BTF_KIND_ARRAY, 0, 0, &[3, 1, 2]);
+        // 4: struct s { 1 a; 3 b; anonymous 5; }, 5: union { anonymous 4; }
+        let members = btf.members(&[("a", 1, 0), ("b", 3, 0), ("", 5, 0)]);
+        btf.ty("s", BTF_KIND_STRUCT, 3, 8, &members);
+        let members = btf.members(&[("", 4, 0)]);
+        btf.ty("", BTF_KIND_UNION, 1, 8, &members);
+        let btf = Btf::parse(&btf.build()).unwrap();
+
+        assert!(btf.member("s", "a").is_err());
+        assert!(btf.member("s", "b").is_err());
+        assert!(btf.member("s", "c").is_err());
+    }
+
+    #[test]
+    fn size_overflow() {
+        let mut btf = Builder::default();
+        // 1: int, 2: int[1 << 31], 3: struct s { int[1 << 31] a; }
+        btf.ty("int", BTF_KIND_INT, 0, 4, &[32]);
+        btf.ty("", BTF_KIND_ARRAY, 0, 0, &[1, 1, 1 << 31]);
+        let members = btf.members(&[("a", 2, 0)]);
+        btf.ty("s", BTF_KIND_STRUCT, 1, 4, &members);
+        let btf = Btf::parse(&btf.build()).unwrap();
+
+        let e = btf.member("s", "a").unwrap_err();
+        assert_eq!(
+            format!("{:#}", e.root_cause()),
+            "size of array type 2 overflows"
+        );
+    }
+}
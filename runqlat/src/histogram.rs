@@ -0,0 +1,77 @@
+use runqlat_common::{Histogram, MAX_SLOTS, slot_bounds};
+
+/// Total number of samples in `hist`.
+pub fn count(hist: &Histogram) -> u64 {
+    hist.iter().map(|&n| u64::from(n)).sum()
+}
+
+/// Adds the counts of `other` to `into`.
+pub fn merge(into: &mut Histogram, other: &Histogram) {
+    for (a, b) in into.iter_mut().zip(other) {
+        *a = a.saturating_add(*b);
+    }
+}
+
+/// Estimates the `q`-quantile (`0.0..=1.0`) of `hist` in us.
+///
+/// Returns the upper bound of the slot holding the quantile, or `None` if `hist` is empty.
+pub fn percentile(hist: &Histogram, q: f64) -> Option<u64> {
+    let total = count(hist);
+    if total == 0 {
+        return None;
+    }
+    let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (slot, &n) in hist.iter().enumerate() {
+        seen += u64::from(n);
+        if seen >= rank {
+            return Some(slot_bounds(slot).1);
+        }
+    }
+    Some(slot_bounds(MAX_SLOTS - 1).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 50 samples of 2-3us, 45 of 8-15us and 5 of 32-63us.
+    fn hist() -> Histogram {
+        let mut hist = [0; MAX_SLOTS];
+        hist[1] = 50;
+        hist[3] = 45;
+        hist[5] = 5;
+        hist
+    }
+
+    #[test]
+    fn percentile_slots() {
+        let hist = hist();
+        assert_eq!(percentile(&hist, 0.5), Some(3));
+        assert_eq!(percentile(&hist, 0.51), Some(15));
+        assert_eq!(percentile(&hist, 0.95), Some(15));
+        assert_eq!(percentile(&hist, 0.96), Some(63));
+        assert_eq!(percentile(&hist, 1.0), Some(63));
+    }
+
+    #[test]
+    fn percentile_bounds() {
+        let hist = hist();
+        assert_eq!(percentile(&hist, 0.0), Some(3));
+        assert_eq!(percentile(&hist, -1.0), Some(3));
+        assert_eq!(percentile(&hist, 2.0), Some(63));
+        assert_eq!(percentile(&[0; MAX_SLOTS], 0.5), None);
+    }
+
+    #[test]
+    fn merge_counts() {
+        let mut into = hist();
+        let mut other = [0; MAX_SLOTS];
+        other[1] = 1;
+        other[5] = u32::MAX;
+        merge(&mut into, &other);
+        assert_eq!(into[1], 51);
+        assert_eq!(into[5], u32::MAX);
+        assert_eq!(count(&into), 51 + 45 + u64::from(u32::MAX));
+    }
+}
@@ -1,23 +1,167 @@
+mod abi;
+mod attach;
+mod btf;
+pub mod histogram;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod objects;
+mod pipeline;
+mod self_monitor;
+mod sink;
+mod snapshot;
+pub mod targets;
+
 use anyhow::{Context, anyhow};
-use aya::programs::RawTracePoint;
-use log::warn;
-use runqlat_common::Histogram;
-use std::collections::HashMap;
+use aya::maps::{MapData, MapError};
+use log::{debug, info, warn};
+use runqlat_common::{COMM_MATCH, Comm, Histogram, TASK_COMM_LEN, TRACK_THREADS, UID_MATCH};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub use attach::{AttachMode, Diagnostics, TaskStateField};
+use attach::{TaskOffsets, TracepointOffsets};
+use objects::Match;
+pub use pipeline::{Pipeline, SinkOptions};
+pub use self_monitor::{
+    SelfMonitor, SelfMonitorOptions, SelfSnapshot, ThreadLatency, self_monitor,
+};
+pub use sink::Sink;
+pub use snapshot::{Snapshot, SnapshotStats, SnapshotStream, Timestamp};
+use targets::{Labels, Process, Target};
 
 pub struct Profiler {
     pub ebpf: aya::Ebpf,
+    diagnostics: Diagnostics,
+    /// File the eBPF object was loaded from.
+    object_file: Option<PathBuf>,
+    /// tgid (process id) -> labels of targets set by [`Profiler::set_targets`].
+    labels: HashMap<u32, Labels>,
+    /// Labels of targets untracked since the last drain, whose final samples may still be in
+    /// `HIST`.
+    retired_labels: HashMap<u32, Labels>,
+    /// tgid (process id) -> start time of the tracked process, to detect reused pids.
+    start_times: HashMap<u32, u64>,
 }
 
 impl Profiler {
+    /// Loads the eBPF programs with the first [`AttachMode`] of [`AttachMode::PRIORITY`] the
+    /// kernel supports.
     pub fn try_new() -> anyhow::Result<Self> {
-        // This will include your eBPF object file as raw bytes at compile-time and load it at
-        // runtime. This approach is recommended for most real-world use cases. If you would
-        // like to specify the eBPF program at runtime rather than at compile-time, you can
-        // reach for `Bpf::load_file` instead.
-        let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-            env!("OUT_DIR"),
-            "/runqlat"
-        )))?;
+        Self::probe(Self::with_mode)
+    }
+
+    /// Loads the eBPF programs of `mode`, see [`AttachMode`].
+    pub fn with_mode(mode: AttachMode) -> anyhow::Result<Self> {
+        Self::load(mode, None)
+    }
+
+    /// Like [`Profiler::try_new`], loading the eBPF object at `path`, e.g. one built for a
+    /// specific kernel, instead of the ones built into the binary. The object must have the maps
+    /// and programs this version of runqlat uses.
+    pub fn from_object_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::probe(|mode| Self::load(mode, Some((path, &data))))
+    }
+
+    /// Like [`Profiler::with_mode`], loading the eBPF object at `path`, see
+    /// [`Profiler::from_object_file`].
+    pub fn from_object_file_with_mode(
+        path: impl AsRef<Path>,
+        mode: AttachMode,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::load(mode, Some((path, &data)))
+    }
+
+    /// Loads the programs with the first mode `load` succeeds with.
+    fn probe(load: impl Fn(AttachMode) -> anyhow::Result<Self>) -> anyhow::Result<Self> {
+        let mut errors = Vec::new();
+        for mode in AttachMode::PRIORITY {
+            match load(mode) {
+                Ok(profiler) => return Ok(profiler),
+                Err(e) => {
+                    info!("cannot attach with {mode}: {e:#}");
+                    errors.push(format!("{mode}: {e:#}"));
+                }
+            }
+        }
+        Err(anyhow!(
+            "failed to attach with any mode, tried {}",
+            errors.join("; ")
+        ))
+    }
+
+    /// Loads the programs of `mode` from the object read from a file, or else from the embedded
+    /// object matching the running kernel.
+    fn load(mode: AttachMode, file: Option<(&Path, &[u8])>) -> anyhow::Result<Self> {
+        // The eBPF objects are included as raw bytes at compile-time, see objects.rs. Objects
+        // read at runtime may be built from another version of runqlat-ebpf, so they are
+        // checked first.
+        let (data, object_release, object_file, object_task_state, object_match) = match file {
+            Some((path, data)) => {
+                let task_state = abi::validate(data, mode)
+                    .with_context(|| format!("incompatible eBPF object {}", path.display()))?;
+                (data, None, Some(path.to_owned()), task_state, Match::Exact)
+            }
+            None => {
+                let (object, object_match) = objects::select(mode)?;
+                (
+                    object.data,
+                    object.release,
+                    None,
+                    object.task_state,
+                    object_match,
+                )
+            }
+        };
+
+        let offsets = match mode {
+            AttachMode::Tracepoint => TracepointOffsets::read()?,
+            _ => TracepointOffsets::default(),
+        };
+        let task_offsets = if !mode.reads_task() {
+            None
+        } else if let Match::Fallback { release } = &object_match {
+            // the offsets of the object are those of another kernel
+            Some(
+                btf::Btf::from_file(btf::VMLINUX_BTF)
+                    .and_then(|btf| TaskOffsets::from_btf(&btf))
+                    .with_context(|| {
+                        format!(
+                            "failed to resolve task_struct offsets of kernel {release}, the \
+                             embedded eBPF objects were built for {}",
+                            objects::releases().join(", ")
+                        )
+                    })?,
+            )
+        } else if mode.needs_btf() {
+            Some(
+                btf::Btf::from_file(btf::VMLINUX_BTF)
+                    .and_then(|btf| TaskOffsets::from_btf(&btf))
+                    .context("failed to resolve task_struct offsets")?,
+            )
+        } else {
+            btf::Btf::from_file(btf::VMLINUX_BTF)
+                .and_then(|btf| TaskOffsets::from_btf(&btf))
+                .inspect(|offsets| debug!("task_struct offsets from BTF: {offsets:?}"))
+                .inspect_err(|e| warn!("using offsets of the committed bindings: {e:#}"))
+                .ok()
+        };
+        let mut loader = aya::EbpfLoader::new();
+        mode.configure(&mut loader, &offsets);
+        if let Some(task_offsets) = &task_offsets {
+            task_offsets.configure(&mut loader);
+        }
+
+        let mut ebpf = loader.load(data)?;
+        mode.attach(&mut ebpf)?;
+
         match aya_log::EbpfLogger::init(&mut ebpf) {
             Err(e) => {
                 // This can happen if you remove all log statements from your eBPF program.
@@ -36,69 +180,617 @@ impl Profiler {
             }
         }
 
-        for tp in ["sched_wakeup", "sched_wakeup_new", "sched_switch"] {
-            let prog: &mut RawTracePoint = ebpf.program_mut(tp).unwrap().try_into()?;
-            prog.load()?;
-            prog.attach(tp)?;
+        let diagnostics = Diagnostics {
+            mode,
+            btf_offsets: task_offsets.is_some(),
+            object_release,
+            task_state: mode
+                .reads_task()
+                .then(|| task_offsets.map_or(object_task_state, |offsets| offsets.state_field)),
+        };
+        if let Some(task_state) = diagnostics.task_state {
+            info!("reading task state from task_struct.{}", task_state.name());
         }
 
-        Ok(Self { ebpf })
+        Ok(Self {
+            ebpf,
+            diagnostics,
+            object_file,
+            labels: HashMap::new(),
+            retired_labels: HashMap::new(),
+            start_times: HashMap::new(),
+        })
+    }
+
+    /// How the loaded programs read the scheduler events.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diagnostics
+    }
+
+    /// File the eBPF object was loaded from, see [`Profiler::from_object_file`], `None` for an
+    /// embedded object.
+    pub fn object_file(&self) -> Option<&Path> {
+        self.object_file.as_deref()
+    }
+
+    /// Stream of snapshots drained every `interval`, starting one interval from now. Intervals
+    /// shorter than a millisecond, including zero, are raised to one.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn stream(&mut self, interval: Duration) -> SnapshotStream<'_> {
+        SnapshotStream::new(self, interval)
     }
 
     pub fn drain_histograms(&mut self) -> anyhow::Result<HashMap<u32, Histogram>> {
+        self.drain_hist_map("HIST")
+    }
+
+    /// Drains per-thread histograms of processes tracked with [`Profiler::track_threads`].
+    pub fn drain_thread_histograms(&mut self) -> anyhow::Result<HashMap<u32, Histogram>> {
+        self.drain_hist_map("THREAD_HIST")
+    }
+
+    fn drain_hist_map(&mut self, name: &str) -> anyhow::Result<HashMap<u32, Histogram>> {
         let hist_map = self
             .ebpf
-            .map_mut("HIST")
-            .ok_or_else(|| anyhow!("HIST map not found"))?;
+            .map_mut(name)
+            .ok_or_else(|| anyhow!("{name} map not found"))?;
 
         let mut hist_map: aya::maps::HashMap<_, u32, Histogram> =
-            aya::maps::HashMap::try_from(hist_map).context("invalid HIST map")?;
+            aya::maps::HashMap::try_from(hist_map)
+                .with_context(|| format!("invalid {name} map"))?;
 
         let out: HashMap<u32, Histogram> = hist_map
             .iter()
             .collect::<Result<_, _>>()
-            .context("failed to read HIST entries")?;
+            .with_context(|| format!("failed to read {name} entries"))?;
 
-        for (pid, _) in &out {
-            let _ = hist_map.remove(pid);
+        for key in out.keys() {
+            let _ = hist_map.remove(key);
         }
 
         Ok(out)
     }
 
     pub fn insert_pids(&mut self, pids: &[u32]) -> anyhow::Result<()> {
+        self.insert_pids_with_flags(pids, 0)
+    }
+
+    /// Tracks `pids` like [`Profiler::insert_pids`] and additionally records a histogram per
+    /// thread, see [`Profiler::drain_thread_histograms`].
+    pub fn track_threads(&mut self, pids: &[u32]) -> anyhow::Result<()> {
+        self.insert_pids_with_flags(pids, TRACK_THREADS)
+    }
+
+    fn insert_pids_with_flags(&mut self, pids: &[u32], flags: u8) -> anyhow::Result<()> {
+        let mut pid_map = self.pid_map()?;
+
+        for pid in pids {
+            pid_map
+                .insert(pid, flags, 0)
+                .context("failed to insert pid into PID map")?;
+        }
+
+        for &pid in pids {
+            if let Some(start_time) = start_time(pid) {
+                self.start_times.insert(pid, start_time);
+            }
+        }
+        self.map_threads(pids, &ProcInfo::default());
+        Ok(())
+    }
+
+    /// Stops tracking `pids`. PIDs that are not tracked are ignored.
+    pub fn remove_pids(&mut self, pids: &[u32]) -> anyhow::Result<()> {
+        let mut pid_map = self.pid_map()?;
+
+        for pid in pids {
+            match pid_map.remove(pid) {
+                Err(e) if !is_not_found(&e) => {
+                    return Err(e).context("failed to remove pid from PID map");
+                }
+                _ => {}
+            }
+        }
+
+        for pid in pids {
+            self.start_times.remove(pid);
+        }
+        Ok(())
+    }
+
+    /// PIDs currently held by the `PID` map.
+    pub fn tracked_pids(&self) -> anyhow::Result<HashSet<u32>> {
         let pid_map = self
             .ebpf
-            .map_mut("PID")
+            .map("PID")
             .ok_or_else(|| anyhow!("PID map not found"))?;
 
-        let mut pid_map: aya::maps::HashMap<_, u32, u8> =
+        let pid_map: aya::maps::HashMap<_, u32, u8> =
             aya::maps::HashMap::try_from(pid_map).context("invalid PID map")?;
 
-        for pid in pids {
+        pid_map
+            .keys()
+            .collect::<Result<_, _>>()
+            .context("failed to read PID entries")
+    }
+
+    /// Makes the `PID` map hold exactly `desired`, inserting and removing only the difference.
+    ///
+    /// PIDs whose process has already exited are not inserted and are reported in
+    /// [`TrackedChanges::exited`]. PIDs that are already tracked keep their flags, so processes
+    /// added with [`Profiler::track_threads`] keep their per-thread histograms. Processes added
+    /// by the eBPF program because of [`Profiler::track_comms`] or [`Profiler::track_uids`] are
+    /// kept until they exit.
+    ///
+    /// Tracked PIDs that were reused by another process are untracked first and reported in
+    /// [`TrackedChanges::recycled`]; the new process is tracked only if it is in `desired`.
+    pub fn set_tracked(
+        &mut self,
+        desired: impl IntoIterator<Item = u32>,
+    ) -> anyhow::Result<TrackedChanges> {
+        self.set_tracked_with(desired, &ProcInfo::default())
+    }
+
+    /// [`Profiler::set_tracked`] with the processes read in `procs` beforehand.
+    fn set_tracked_with(
+        &mut self,
+        desired: impl IntoIterator<Item = u32>,
+        procs: &ProcInfo,
+    ) -> anyhow::Result<TrackedChanges> {
+        let desired: HashSet<u32> = desired.into_iter().collect();
+        let mut changes = TrackedChanges::default();
+        let mut current: HashMap<u32, u8> = self
+            .pid_map()?
+            .iter()
+            .collect::<Result<_, _>>()
+            .context("failed to read PID entries")?;
+
+        changes.recycled = self.untrack_recycled(current.keys(), procs)?;
+        for pid in &changes.recycled {
+            current.remove(pid);
+        }
+
+        let mut started = Vec::new();
+        let mut pid_map = self.pid_map()?;
+
+        for (&pid, &flags) in &current {
+            let matched = flags & (COMM_MATCH | UID_MATCH) != 0;
+            if desired.contains(&pid) || (matched && procs.start_time(pid).is_some()) {
+                continue;
+            }
+            match pid_map.remove(&pid) {
+                Ok(()) => changes.removed.push(pid),
+                // Removed concurrently, e.g. by another reconciler.
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(e).context("failed to remove pid from PID map"),
+            }
+        }
+
+        for &pid in desired.iter().filter(|pid| !current.contains_key(pid)) {
+            let Some(start_time) = procs.start_time(pid) else {
+                changes.exited.push(pid);
+                continue;
+            };
             pid_map
                 .insert(pid, 0, 0)
                 .context("failed to insert pid into PID map")?;
+            changes.added.push(pid);
+            started.push((pid, start_time));
+        }
+
+        for pid in &changes.removed {
+            self.start_times.remove(pid);
+        }
+        self.start_times.extend(started);
+        self.map_threads(&changes.added, procs);
+
+        changes.added.sort_unstable();
+        changes.removed.sort_unstable();
+        changes.exited.sort_unstable();
+        Ok(changes)
+    }
+
+    /// Untracks the processes among `pids` whose pid was reused by another process since they
+    /// were tracked, and forgets their labels. Returns the reused pids.
+    ///
+    /// Processes added by the eBPF program are identified by the start time first seen here.
+    pub(crate) fn untrack_recycled<'a>(
+        &mut self,
+        pids: impl IntoIterator<Item = &'a u32>,
+        procs: &ProcInfo,
+    ) -> anyhow::Result<Vec<u32>> {
+        let mut recycled = Vec::new();
+        for &pid in pids {
+            // Exited processes are untracked by the next set_tracked.
+            let Some(start_time) = procs.start_time(pid) else {
+                continue;
+            };
+            match self.start_times.insert(pid, start_time) {
+                Some(tracked) if tracked != start_time => recycled.push(pid),
+                _ => {}
+            }
+        }
+
+        self.remove_pids(&recycled)?;
+        for pid in &recycled {
+            self.labels.remove(pid);
+            self.retired_labels.remove(pid);
+        }
+        recycled.sort_unstable();
+        Ok(recycled)
+    }
+
+    /// Like [`Profiler::set_tracked`], additionally remembering the labels of each target so
+    /// that they are attached to the snapshots of its histograms.
+    pub fn set_targets(
+        &mut self,
+        targets: impl IntoIterator<Item = Target>,
+    ) -> anyhow::Result<TrackedChanges> {
+        self.set_targets_with(targets, &ProcInfo::default())
+    }
+
+    /// [`Profiler::set_targets`] with the processes read in `procs` beforehand, e.g. off the
+    /// async runtime and the lock of the profiler, see [`ProcInfo::read`].
+    pub(crate) fn set_targets_with(
+        &mut self,
+        targets: impl IntoIterator<Item = Target>,
+        procs: &ProcInfo,
+    ) -> anyhow::Result<TrackedChanges> {
+        let labels: HashMap<u32, Labels> = targets
+            .into_iter()
+            .map(|Target { pid, labels }| (pid, labels))
+            .collect();
+        let changes = self.set_tracked_with(labels.keys().copied(), procs)?;
+
+        for pid in &changes.removed {
+            if let Some(labels) = self.labels.remove(pid) {
+                self.retired_labels.insert(*pid, labels);
+            }
+        }
+        for pid in &changes.exited {
+            self.labels.remove(pid);
+        }
+        for (pid, labels) in labels {
+            if !changes.exited.contains(&pid) {
+                self.labels.insert(pid, labels);
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Labels of `pids` for a snapshot drained just now. Forgets the labels of retired targets.
+    ///
+    /// Processes without target labels, e.g. those added by the eBPF program, are labeled with
+    /// their `comm` and `uid` while they run.
+    pub(crate) fn take_labels<'a>(
+        &mut self,
+        pids: impl IntoIterator<Item = &'a u32>,
+    ) -> HashMap<u32, Labels> {
+        let mut retired = std::mem::take(&mut self.retired_labels);
+        pids.into_iter()
+            .filter_map(|pid| {
+                let labels = match self.labels.get(pid) {
+                    Some(labels) => labels.clone(),
+                    None => retired.remove(pid).or_else(|| process_labels(*pid))?,
+                };
+                Some((*pid, labels))
+            })
+            .collect()
+    }
+
+    /// Tracks every process named like one of `comms`, as matched by the eBPF program: processes
+    /// are added to the `PID` map when they exec or are first woken up, without waiting for
+    /// userspace. Names are matched against the first 15 bytes of the process's comm.
+    pub fn track_comms(&mut self, comms: &[&str]) -> anyhow::Result<()> {
+        let mut comm_map = self.comm_map()?;
+
+        for comm in comms {
+            comm_map
+                .insert(comm_key(comm), 0, 0)
+                .context("failed to insert comm into COMM map")?;
+        }
+
+        self.update_matching()
+    }
+
+    /// Stops matching `comms` in the eBPF program. Processes named like `comms` that were already
+    /// added stay tracked until the next [`Profiler::set_tracked`].
+    pub fn untrack_comms(&mut self, comms: &[&str]) -> anyhow::Result<()> {
+        let mut comm_map = self.comm_map()?;
+
+        let removed: HashSet<Comm> = comms.iter().map(|comm| comm_key(comm)).collect();
+        for comm in &removed {
+            match comm_map.remove(comm) {
+                Err(e) if !is_not_found(&e) => {
+                    return Err(e).context("failed to remove comm from COMM map");
+                }
+                _ => {}
+            }
+        }
+
+        self.update_matching()?;
+        self.clear_flag(COMM_MATCH, |pid| {
+            Process::new(Path::new("/proc"), pid)
+                .comm()
+                .ok()
+                .is_none_or(|comm| removed.contains(&comm_key(&comm)))
+        })
+    }
+
+    /// Names held by the `COMM` map.
+    pub fn tracked_comms(&self) -> anyhow::Result<HashSet<String>> {
+        let comm_map = self
+            .ebpf
+            .map("COMM")
+            .ok_or_else(|| anyhow!("COMM map not found"))?;
+
+        let comm_map: aya::maps::HashMap<_, Comm, u8> =
+            aya::maps::HashMap::try_from(comm_map).context("invalid COMM map")?;
+
+        comm_map
+            .keys()
+            .map(|comm| {
+                let comm = comm?;
+                let len = comm.iter().position(|&b| b == 0).unwrap_or(comm.len());
+                Ok(String::from_utf8_lossy(&comm[..len]).into_owned())
+            })
+            .collect::<Result<_, MapError>>()
+            .context("failed to read COMM entries")
+    }
+
+    /// Tracks every process of the users `uids`, as matched by the eBPF program on the real uid of
+    /// the task's credentials, like [`Profiler::track_comms`].
+    pub fn track_uids(&mut self, uids: &[u32]) -> anyhow::Result<()> {
+        let mut uid_map = self.uid_map()?;
+
+        for uid in uids {
+            uid_map
+                .insert(uid, 0, 0)
+                .context("failed to insert uid into UID map")?;
+        }
+
+        self.update_matching()
+    }
+
+    /// Stops matching `uids` in the eBPF program. Processes of `uids` that were already added stay
+    /// tracked until the next [`Profiler::set_tracked`].
+    pub fn untrack_uids(&mut self, uids: &[u32]) -> anyhow::Result<()> {
+        let mut uid_map = self.uid_map()?;
+
+        let removed: HashSet<u32> = uids.iter().copied().collect();
+        for uid in &removed {
+            match uid_map.remove(uid) {
+                Err(e) if !is_not_found(&e) => {
+                    return Err(e).context("failed to remove uid from UID map");
+                }
+                _ => {}
+            }
+        }
+
+        self.update_matching()?;
+        self.clear_flag(UID_MATCH, |pid| {
+            Process::new(Path::new("/proc"), pid)
+                .uid()
+                .ok()
+                .is_none_or(|uid| removed.contains(&uid))
+        })
+    }
+
+    /// User ids held by the `UID` map.
+    pub fn tracked_uids(&self) -> anyhow::Result<HashSet<u32>> {
+        let uid_map = self
+            .ebpf
+            .map("UID")
+            .ok_or_else(|| anyhow!("UID map not found"))?;
+
+        let uid_map: aya::maps::HashMap<_, u32, u8> =
+            aya::maps::HashMap::try_from(uid_map).context("invalid UID map")?;
+
+        uid_map
+            .keys()
+            .collect::<Result<_, _>>()
+            .context("failed to read UID entries")
+    }
+
+    /// Maps the threads of `pids` to their process in the `TGID` map, which the programs of
+    /// [`AttachMode::Tracepoint`] cannot read from the task. Threads started later are mapped by
+    /// the programs themselves.
+    fn map_threads(&mut self, pids: &[u32], procs: &ProcInfo) {
+        if !self.maps_threads() {
+            return;
+        }
+        let Some(tgid_map) = self.ebpf.map_mut("TGID") else {
+            warn!("TGID map not found");
+            return;
+        };
+        let mut tgid_map: aya::maps::HashMap<_, u32, u32> = match tgid_map.try_into() {
+            Ok(tgid_map) => tgid_map,
+            Err(e) => {
+                warn!("invalid TGID map: {e}");
+                return;
+            }
+        };
+
+        for &pid in pids {
+            for tid in procs.threads(pid) {
+                if let Err(e) = tgid_map.insert(tid, pid, 0) {
+                    debug!("failed to insert thread {tid} of {pid} into TGID map: {e}");
+                }
+            }
+        }
+    }
+
+    /// Whether userspace maps the threads of tracked processes, see [`Profiler::map_threads`].
+    pub(crate) fn maps_threads(&self) -> bool {
+        !self.diagnostics.mode.reads_task()
+    }
+
+    /// Removes `flag` from the processes in the `PID` map `matched_by` it, e.g. so that
+    /// [`Profiler::set_tracked`] untracks processes matched by the eBPF program.
+    fn clear_flag(&mut self, flag: u8, matched_by: impl Fn(u32) -> bool) -> anyhow::Result<()> {
+        let mut pid_map = self.pid_map()?;
+        let flagged: Vec<(u32, u8)> = pid_map
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|&(pid, flags)| flags & flag != 0 && matched_by(pid))
+            .collect();
+        for (pid, flags) in flagged {
+            let _ = pid_map.insert(pid, flags & !flag, 0);
         }
 
         Ok(())
     }
 
-    pub fn remove_pids(&mut self, pids: &[u32]) -> anyhow::Result<()> {
+    /// Enables in the `MATCH` map the matching of the kinds of names held by the match maps.
+    fn update_matching(&mut self) -> anyhow::Result<()> {
+        let mut kinds = 0;
+        if self.comm_map()?.keys().next().is_some() {
+            kinds |= COMM_MATCH;
+        }
+        if self.uid_map()?.keys().next().is_some() {
+            kinds |= UID_MATCH;
+        }
+
+        let match_map = self
+            .ebpf
+            .map_mut("MATCH")
+            .ok_or_else(|| anyhow!("MATCH map not found"))?;
+        let mut match_map: aya::maps::Array<_, u8> =
+            aya::maps::Array::try_from(match_map).context("invalid MATCH map")?;
+        match_map
+            .set(0, kinds, 0)
+            .context("failed to update MATCH map")
+    }
+
+    fn comm_map(&mut self) -> anyhow::Result<aya::maps::HashMap<&mut MapData, Comm, u8>> {
+        let comm_map = self
+            .ebpf
+            .map_mut("COMM")
+            .ok_or_else(|| anyhow!("COMM map not found"))?;
+
+        aya::maps::HashMap::try_from(comm_map).context("invalid COMM map")
+    }
+
+    fn uid_map(&mut self) -> anyhow::Result<aya::maps::HashMap<&mut MapData, u32, u8>> {
+        let uid_map = self
+            .ebpf
+            .map_mut("UID")
+            .ok_or_else(|| anyhow!("UID map not found"))?;
+
+        aya::maps::HashMap::try_from(uid_map).context("invalid UID map")
+    }
+
+    fn pid_map(&mut self) -> anyhow::Result<aya::maps::HashMap<&mut MapData, u32, u8>> {
         let pid_map = self
             .ebpf
             .map_mut("PID")
             .ok_or_else(|| anyhow!("PID map not found"))?;
 
-        let mut pid_map: aya::maps::HashMap<_, u32, u8> =
-            aya::maps::HashMap::try_from(pid_map).context("invalid PID map")?;
+        aya::maps::HashMap::try_from(pid_map).context("invalid PID map")
+    }
+}
+
+/// Changes applied by [`Profiler::set_tracked`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackedChanges {
+    /// PIDs inserted into the `PID` map.
+    pub added: Vec<u32>,
+    /// PIDs removed from the `PID` map.
+    pub removed: Vec<u32>,
+    /// Desired PIDs not inserted because their process had already exited.
+    pub exited: Vec<u32>,
+    /// PIDs untracked because their process exited and the pid was reused by another process.
+    /// PIDs that are still desired are tracked again and also listed in `added`.
+    pub recycled: Vec<u32>,
+}
+
+impl TrackedChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.exited.is_empty()
+            && self.recycled.is_empty()
+    }
+}
+
+/// `COMM` map key of `comm`, truncated like the kernel does.
+fn comm_key(comm: &str) -> Comm {
+    let mut key = [0; TASK_COMM_LEN];
+    let len = comm.len().min(TASK_COMM_LEN - 1);
+    key[..len].copy_from_slice(&comm.as_bytes()[..len]);
+    key
+}
 
+/// Start times and threads of processes read from procfs ahead of updating the `PID` map, so
+/// that it is not read while the profiler is locked. Processes not read are read on demand.
+#[derive(Debug, Default)]
+pub(crate) struct ProcInfo {
+    /// pid -> start time, `None` if the process does not exist.
+    start_times: HashMap<u32, Option<u64>>,
+    /// pid -> ids of its threads.
+    threads: HashMap<u32, Vec<u32>>,
+}
+
+impl ProcInfo {
+    /// Reads the start times of `pids`, and their threads if `threads`, see
+    /// [`Profiler::maps_threads`].
+    pub(crate) fn read(pids: impl IntoIterator<Item = u32>, threads: bool) -> Self {
+        let mut procs = Self::default();
         for pid in pids {
-            pid_map
-                .remove(pid)
-                .context("failed to insert pid into PID map")?;
+            procs.start_times.insert(pid, start_time(pid));
+            if threads {
+                procs.threads.insert(pid, read_threads(pid));
+            }
         }
+        procs
+    }
 
-        Ok(())
+    fn start_time(&self, pid: u32) -> Option<u64> {
+        match self.start_times.get(&pid) {
+            Some(start_time) => *start_time,
+            None => start_time(pid),
+        }
+    }
+
+    fn threads(&self, pid: u32) -> Vec<u32> {
+        match self.threads.get(&pid) {
+            Some(threads) => threads.clone(),
+            None => read_threads(pid),
+        }
+    }
+}
+
+/// Start time of process `pid`, or `None` if it does not exist.
+fn start_time(pid: u32) -> Option<u64> {
+    Process::new(Path::new("/proc"), pid).start_time().ok()
+}
+
+/// Ids of the threads of process `pid`, empty if it does not exist.
+fn read_threads(pid: u32) -> Vec<u32> {
+    let Ok(tasks) = fs::read_dir(format!("/proc/{pid}/task")) else {
+        return Vec::new();
+    };
+    tasks
+        .filter_map(|task| task.ok()?.file_name().to_str()?.parse().ok())
+        .collect()
+}
+
+/// `comm` and `uid` labels of a running process.
+fn process_labels(pid: u32) -> Option<Labels> {
+    let process = Process::new(Path::new("/proc"), pid);
+    let mut labels = Labels::new();
+    labels.insert("comm".to_owned(), process.comm().ok()?);
+    labels.insert("uid".to_owned(), process.uid().ok()?.to_string());
+    Some(labels)
+}
+
+/// Whether `e` means the key was not in the map.
+fn is_not_found(e: &MapError) -> bool {
+    match e {
+        MapError::KeyNotFound => true,
+        MapError::SyscallError(e) => e.io_error.raw_os_error() == Some(libc::ENOENT),
+        _ => false,
     }
 }
@@ -1,9 +1,147 @@
 #[rustfmt::skip]
 use log::debug;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Parser;
+use regex::Regex;
+use runqlat::{
+    AttachMode, Pipeline, Profiler, Sink, SinkOptions, Snapshot,
+    targets::{ProcessMatcher, Reconciler, StaticTargets, SystemdSource},
+};
+use runqlat_common::{Histogram, slot_bounds};
 use tokio::signal;
 
+/// Continuously measures run queue latencies of selected processes.
+///
+/// Processes given by --pid and all processes of the systemd units given by --unit are always
+/// tracked. The other target options select processes from /proc: a process must match one value
+/// of every kind of option given, e.g. `--comm nginx --uid 33`. Without any target option,
+/// runqlat tracks itself.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Track the process with this PID.
+    #[arg(long, value_name = "PID")]
+    pid: Vec<u32>,
+    /// Track processes whose name (comm) is exactly NAME.
+    #[arg(long, value_name = "NAME")]
+    comm: Vec<String>,
+    /// Track processes whose name (comm) matches REGEX.
+    #[arg(long, value_name = "REGEX")]
+    comm_regex: Vec<Regex>,
+    /// Track processes whose command line matches REGEX.
+    #[arg(long, value_name = "REGEX")]
+    cmdline: Vec<Regex>,
+    /// Track processes running the executable at PATH.
+    #[arg(long, value_name = "PATH")]
+    exe: Vec<PathBuf>,
+    /// Track processes of the user with this UID.
+    #[arg(long, value_name = "UID")]
+    uid: Vec<u32>,
+    /// Track all processes of the systemd unit NAME, e.g. postgresql.service or machine.slice.
+    #[arg(long, value_name = "NAME")]
+    unit: Vec<String>,
+    /// Print one histogram per value of the target label LABEL, e.g. uid or unit, instead of one
+    /// per process.
+    #[arg(long, value_name = "LABEL")]
+    group_by: Option<String>,
+    /// Attach to the regular sched tracepoints instead of raw tracepoints, so that the binary
+    /// does not depend on the task_struct layout of the kernel it was built for.
+    #[arg(long, conflicts_with = "attach")]
+    portable: bool,
+    /// Hook into the scheduler with MODE: tp_btf, raw_tracepoint, fentry, kprobe or tracepoint.
+    /// By default, the first mode the kernel supports is used, in this order.
+    #[arg(long, value_name = "MODE")]
+    attach: Option<AttachMode>,
+    /// Load the eBPF object at PATH, e.g. one built for a specific kernel, instead of the ones
+    /// built into runqlat.
+    #[arg(long, value_name = "PATH")]
+    bpf_object: Option<PathBuf>,
+    /// Seconds between histogram reports and target rescans.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    interval: u64,
+}
+
+impl Args {
+    fn matcher(&self) -> ProcessMatcher {
+        let mut matcher = ProcessMatcher::new();
+        for comm in &self.comm {
+            matcher = matcher.comm(comm.clone());
+        }
+        for regex in &self.comm_regex {
+            matcher = matcher.comm_regex(regex.clone());
+        }
+        for regex in &self.cmdline {
+            matcher = matcher.cmdline(regex.clone());
+        }
+        for exe in &self.exe {
+            matcher = matcher.exe(exe.clone());
+        }
+        for &uid in &self.uid {
+            matcher = matcher.uid(uid);
+        }
+        matcher
+    }
+}
+
+/// Prints histograms in the format of bcc's runqlat.
+struct PrintSink {
+    group_by: Option<String>,
+}
+
+impl Sink for PrintSink {
+    async fn export(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        if let Some(key) = &self.group_by {
+            for (value, hist) in snapshot.aggregate(key) {
+                println!("\n{key} = {value}");
+                print_histogram(&hist);
+            }
+            return Ok(());
+        }
+
+        let mut pids: Vec<_> = snapshot.histograms.keys().collect();
+        pids.sort_unstable();
+        for pid in pids {
+            let hist = &snapshot.histograms[pid];
+            let labels = snapshot
+                .labels
+                .get(pid)
+                .map(|labels| {
+                    labels
+                        .iter()
+                        .map(|(key, value)| format!("{key}={value}"))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_default();
+            println!("\npid = {pid} {labels}");
+            print_histogram(hist);
+        }
+        Ok(())
+    }
+}
+
+fn print_histogram(hist: &Histogram) {
+    println!("{:>20} : {:<8} distribution", "usecs", "count");
+
+    let max = hist.iter().copied().max().unwrap_or(0).max(1);
+    let last = hist.iter().rposition(|&n| n > 0).unwrap_or(0);
+    for (slot, &count) in hist.iter().enumerate().take(last + 1) {
+        let (lo, hi) = slot_bounds(slot);
+        let stars = (count as u64 * 40 / max as u64) as usize;
+        println!(
+            "{lo:>10} -> {hi:<10} : {count:<8} |{:<40}|",
+            "*".repeat(stars)
+        );
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     env_logger::init();
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
@@ -18,19 +156,51 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // Initialize the eBPF profiler
-    let mut profiler = runqlat::Profiler::try_new()?;
+    let mode = args
+        .attach
+        .or(args.portable.then_some(AttachMode::Tracepoint));
+    let profiler = match (&args.bpf_object, mode) {
+        (Some(path), Some(mode)) => Profiler::from_object_file_with_mode(path, mode)?,
+        (Some(path), None) => Profiler::from_object_file(path)?,
+        (None, Some(mode)) => Profiler::with_mode(mode)?,
+        (None, None) => Profiler::try_new()?,
+    };
+    let profiler = Arc::new(Mutex::new(profiler));
+    let interval = Duration::from_secs(args.interval.max(1));
 
-    // Need additional logic here to populate PID map in eBPF program.
-    // For example, track all processes of certain containers or users.
-    profiler.insert_pids(&[std::process::id()])?;
+    // Keep the PID map in sync with the selected targets.
+    let mut reconciler = Reconciler::new(profiler.clone(), interval);
+    let matcher = args.matcher();
+    let mut pids = args.pid;
+    if pids.is_empty() && matcher.is_empty() && args.unit.is_empty() {
+        pids.push(std::process::id());
+    }
+    if !pids.is_empty() {
+        reconciler.add_source(StaticTargets::from_pids(pids));
+    }
+    if !matcher.is_empty() {
+        reconciler.add_source(matcher.into_source());
+    }
+    if !args.unit.is_empty() {
+        reconciler.add_source(SystemdSource::new(args.unit));
+    }
 
     // Periodically read histograms from eBPF maps.
-    // Use these histograms as needed, e.g. export as metrics.
-    let _histograms = profiler.drain_histograms();
+    // Add sinks as needed, e.g. export as metrics.
+    let mut pipeline = Pipeline::new(profiler, interval);
+    let print = PrintSink {
+        group_by: args.group_by,
+    };
+    pipeline.add_sink("stdout", print, SinkOptions::default());
 
-    let ctrl_c = signal::ctrl_c();
-    println!("Waiting for Ctrl-C...");
-    ctrl_c.await?;
+    println!("Tracing run queue latency... Hit Ctrl-C to end.");
+    let ctrl_c = async {
+        let _ = signal::ctrl_c().await;
+    };
+    tokio::select! {
+        result = reconciler.run(std::future::pending()) => result?,
+        result = pipeline.run(ctrl_c) => result?,
+    }
     println!("Exiting...");
 
     Ok(())
@@ -0,0 +1,184 @@
+use ::metrics::Label;
+use runqlat_common::{MAX_SLOTS, slot_bounds};
+
+use crate::{Sink, Snapshot};
+
+/// Histogram of run queue latencies in us, labeled by `pid` and the labels of the target.
+pub const LATENCY_HISTOGRAM: &str = "runqlat_runq_latency_us";
+/// Number of run queue latency samples, labeled like [`LATENCY_HISTOGRAM`].
+pub const EVENTS_COUNTER: &str = "runqlat_events_total";
+/// Number of snapshots a pipeline sink dropped because its queue was full or its export task
+/// exited, labeled by `sink`.
+pub const DROPPED_COUNTER: &str = "runqlat_snapshots_dropped_total";
+
+/// Records `snapshot` into the globally installed `metrics` recorder.
+///
+/// Each sample is recorded at the upper bound of its log2 slot.
+pub fn record(snapshot: &Snapshot) {
+    for (pid, hist) in &snapshot.histograms {
+        let mut labels = vec![Label::new("pid", pid.to_string())];
+        if let Some(target_labels) = snapshot.labels.get(pid) {
+            labels.extend(
+                target_labels
+                    .iter()
+                    .map(|(key, value)| Label::new(key.clone(), value.clone())),
+            );
+        }
+        let histogram = ::metrics::histogram!(LATENCY_HISTOGRAM, labels.clone());
+        let mut events = 0u64;
+        for (slot, &count) in hist.iter().enumerate().take(MAX_SLOTS) {
+            if count == 0 {
+                continue;
+            }
+            let (_, hi) = slot_bounds(slot);
+            histogram.record_many(hi as f64, count as usize);
+            events += u64::from(count);
+        }
+        ::metrics::counter!(EVENTS_COUNTER, labels).increment(events);
+    }
+}
+
+/// [`Sink`] forwarding snapshots to the global `metrics` recorder.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetricsSink;
+
+impl Sink for MetricsSink {
+    async fn export(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        record(snapshot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex},
+    };
+
+    use ::metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+
+    use super::*;
+    use crate::{SnapshotStats, Timestamp, targets::Labels};
+
+    /// Values recorded by [`Debugging`] by metric, written like `name{key=value,..}`.
+    #[derive(Default)]
+    struct Recorded {
+        counters: Mutex<BTreeMap<String, u64>>,
+        histograms: Mutex<BTreeMap<String, Vec<f64>>>,
+    }
+
+    struct Handle {
+        key: String,
+        recorded: Arc<Recorded>,
+    }
+
+    impl CounterFn for Handle {
+        fn increment(&self, value: u64) {
+            *self
+                .recorded
+                .counters
+                .lock()
+                .unwrap()
+                .entry(self.key.clone())
+                .or_default() += value;
+        }
+
+        fn absolute(&self, value: u64) {
+            let mut counters = self.recorded.counters.lock().unwrap();
+            let counter = counters.entry(self.key.clone()).or_default();
+            *counter = (*counter).max(value);
+        }
+    }
+
+    impl HistogramFn for Handle {
+        fn record(&self, value: f64) {
+            self.recorded
+                .histograms
+                .lock()
+                .unwrap()
+                .entry(self.key.clone())
+                .or_default()
+                .push(value);
+        }
+    }
+
+    /// Recorder keeping every counter and histogram value in memory.
+    #[derive(Default)]
+    struct Debugging(Arc<Recorded>);
+
+    impl Debugging {
+        fn handle(&self, key: &Key) -> Arc<Handle> {
+            let mut labels: Vec<_> = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            labels.sort();
+            Arc::new(Handle {
+                key: format!("{}{{{}}}", key.name(), labels.join(",")),
+                recorded: self.0.clone(),
+            })
+        }
+    }
+
+    impl Recorder for Debugging {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.handle(key))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.handle(key))
+        }
+    }
+
+    #[test]
+    fn record_snapshot() {
+        let mut a = [0; MAX_SLOTS];
+        a[0] = 2;
+        a[3] = 1;
+        let mut b = [0; MAX_SLOTS];
+        b[1] = 3;
+        let labels = Labels::from([("comm".to_owned(), "postgres".to_owned())]);
+        let snapshot = Snapshot {
+            start: Timestamp::now(),
+            end: Timestamp::now(),
+            histograms: HashMap::from([(10, a), (20, b)]),
+            labels: HashMap::from([(10, labels)]),
+            stats: SnapshotStats::default(),
+        };
+
+        let recorder = Debugging::default();
+        ::metrics::with_local_recorder(&recorder, || record(&snapshot));
+
+        let histograms = recorder.0.histograms.lock().unwrap().clone();
+        assert_eq!(
+            histograms,
+            BTreeMap::from([
+                (
+                    format!("{LATENCY_HISTOGRAM}{{comm=postgres,pid=10}}"),
+                    vec![1.0, 1.0, 15.0]
+                ),
+                (format!("{LATENCY_HISTOGRAM}{{pid=20}}"), vec![3.0; 3]),
+            ])
+        );
+        let counters = recorder.0.counters.lock().unwrap().clone();
+        assert_eq!(
+            counters,
+            BTreeMap::from([
+                (format!("{EVENTS_COUNTER}{{comm=postgres,pid=10}}"), 3),
+                (format!("{EVENTS_COUNTER}{{pid=20}}"), 3),
+            ])
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs;
+
+use anyhow::{Context as _, bail};
+use log::{debug, info};
+
+use crate::{AttachMode, TaskStateField, btf};
+
+/// Release of the running kernel, like `uname -r`.
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+
+/// eBPF object built into the binary.
+#[derive(Debug)]
+pub(crate) struct Object {
+    /// Release of the kernel the object was built for, `None` for the object built with the
+    /// committed bindings.
+    pub(crate) release: Option<&'static str>,
+    /// [`btf::checksum`] of the BTF the object was built from.
+    pub(crate) btf_checksum: Option<u64>,
+    /// State field read at the offsets of the bindings the object was built with, i.e. when the
+    /// running kernel has no BTF.
+    pub(crate) task_state: TaskStateField,
+    pub(crate) data: &'static [u8],
+}
+
+// One object per kernel of `RUNQLAT_KERNELS` at build time, or the one built with the committed
+// bindings, see build.rs.
+include!(concat!(env!("OUT_DIR"), "/objects.rs"));
+
+/// How [`select`] chose the object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Match {
+    /// Built for the running kernel, or the only object, built with the committed bindings.
+    Exact,
+    /// Built for another kernel than `release`, the running one, so its offsets must not be used.
+    Fallback { release: String },
+}
+
+/// Releases of the kernels the embedded objects were built for.
+pub(crate) fn releases() -> Vec<&'static str> {
+    OBJECTS.iter().filter_map(|object| object.release).collect()
+}
+
+/// Object built for the running kernel, matched by its BTF or else its release.
+///
+/// Kernels with BTF may also use an object built for another kernel, as the offsets of the
+/// fields read must then be resolved from their BTF, and so may modes reading no `task_struct`.
+/// Otherwise, a kernel no object was built for is refused rather than read at the wrong offsets.
+pub(crate) fn select(mode: AttachMode) -> anyhow::Result<(&'static Object, Match)> {
+    if let [object] = OBJECTS
+        && object.release.is_none()
+    {
+        return Ok((object, Match::Exact));
+    }
+
+    let checksum = fs::read(btf::VMLINUX_BTF)
+        .ok()
+        .map(|data| btf::checksum(&data));
+    if let Some(object) = OBJECTS
+        .iter()
+        .find(|object| checksum.is_some() && object.btf_checksum == checksum)
+    {
+        debug!("using eBPF object built from the BTF of the running kernel");
+        return Ok((object, Match::Exact));
+    }
+
+    let release =
+        fs::read_to_string(OSRELEASE).with_context(|| format!("failed to read {OSRELEASE}"))?;
+    let release = release.trim();
+    if let Some(object) = OBJECTS
+        .iter()
+        .find(|object| object.release == Some(release))
+    {
+        debug!("using eBPF object built for {release}");
+        return Ok((object, Match::Exact));
+    }
+
+    let releases = releases();
+    if checksum.is_some() || !mode.reads_task() {
+        info!(
+            "no eBPF object built for {release}, using the one built for {}",
+            releases[0]
+        );
+        let release = release.to_owned();
+        return Ok((&OBJECTS[0], Match::Fallback { release }));
+    }
+    bail!(
+        "no eBPF object built for kernel {release}, which has no BTF to resolve offsets from; \
+         objects were built for {}",
+        releases.join(", ")
+    )
+}
@@ -0,0 +1,296 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use log::warn;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+use crate::{
+    Profiler, Sink, Snapshot,
+    snapshot::{MIN_PERIOD, Ticker, Timestamp},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SinkOptions {
+    /// Max number of snapshots queued for the sink before new ones are dropped.
+    pub queue_capacity: usize,
+    /// Max time a single `Sink::export` call may take.
+    pub timeout: Duration,
+}
+
+impl Default for SinkOptions {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 4,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+struct SinkHandle {
+    name: String,
+    tx: mpsc::Sender<Arc<Snapshot>>,
+    task: JoinHandle<()>,
+    dropped: u64,
+    /// The export task exited, e.g. because the sink panicked; no more snapshots are sent.
+    closed: bool,
+}
+
+/// Drains the [`Profiler`] on a fixed interval and fans snapshots out to sinks.
+///
+/// Every sink gets a bounded queue and a task of its own. A sink that is slow, fails or panics
+/// only loses its own snapshots; collection and the other sinks keep going.
+pub struct Pipeline {
+    profiler: Arc<Mutex<Profiler>>,
+    interval: Duration,
+    sinks: Sinks,
+}
+
+impl Pipeline {
+    /// Pipeline collecting every `interval`. Intervals shorter than a millisecond, including
+    /// zero, are raised to one.
+    pub fn new(profiler: Arc<Mutex<Profiler>>, interval: Duration) -> Self {
+        Self {
+            profiler,
+            interval: interval.max(MIN_PERIOD),
+            sinks: Sinks::default(),
+        }
+    }
+
+    /// Registers a sink and spawns its export task. Must be called within a tokio runtime.
+    pub fn add_sink<S: Sink>(
+        &mut self,
+        name: impl Into<String>,
+        sink: S,
+        options: SinkOptions,
+    ) -> &mut Self {
+        self.sinks.add(name.into(), sink, options);
+        self
+    }
+
+    /// Collects snapshots until `shutdown` resolves, then waits for sinks to flush their queues.
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let mut ticker = Ticker::new(self.interval);
+        let mut start = Timestamp::now();
+
+        tokio::pin!(shutdown);
+        let result = loop {
+            let missed_ticks = tokio::select! {
+                _ = &mut shutdown => break Ok(()),
+                missed_ticks = ticker.tick() => missed_ticks,
+            };
+
+            let snapshot = Snapshot::collect(
+                &mut self.profiler.lock().unwrap_or_else(PoisonError::into_inner),
+                start,
+                missed_ticks,
+            );
+            let snapshot = match snapshot {
+                Ok(snapshot) => Arc::new(snapshot),
+                Err(e) => break Err(e),
+            };
+            start = snapshot.end;
+
+            self.sinks.send(&snapshot);
+        };
+
+        self.sinks.close().await;
+        result
+    }
+}
+
+/// Sinks of a [`Pipeline`], each fed through its queue by its export task.
+#[derive(Default)]
+struct Sinks {
+    handles: Vec<SinkHandle>,
+}
+
+impl Sinks {
+    fn add<S: Sink>(&mut self, name: String, sink: S, options: SinkOptions) {
+        let (tx, rx) = mpsc::channel(options.queue_capacity.max(1));
+        let task = tokio::spawn(drive_sink(name.clone(), sink, rx, options.timeout));
+        self.handles.push(SinkHandle {
+            name,
+            tx,
+            task,
+            dropped: 0,
+            closed: false,
+        });
+    }
+
+    /// Queues `snapshot` for every sink still running, without waiting for room in the queues.
+    fn send(&mut self, snapshot: &Arc<Snapshot>) {
+        for sink in self.handles.iter_mut().filter(|sink| !sink.closed) {
+            match sink.tx.try_send(snapshot.clone()) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    sink.dropped += 1;
+                    warn!(
+                        "sink {}: queue full, dropped snapshot ({} dropped so far)",
+                        sink.name, sink.dropped
+                    );
+                }
+                Err(TrySendError::Closed(_)) => {
+                    sink.dropped += 1;
+                    sink.closed = true;
+                    warn!(
+                        "sink {}: export task exited, no longer sending snapshots",
+                        sink.name
+                    );
+                }
+            }
+            #[cfg(feature = "metrics")]
+            ::metrics::counter!(
+                crate::metrics::DROPPED_COUNTER,
+                "sink" => sink.name.clone()
+            )
+            .increment(1);
+        }
+    }
+
+    /// Waits for the sinks to export the queued snapshots.
+    async fn close(self) {
+        for SinkHandle {
+            name,
+            tx,
+            task,
+            dropped,
+            ..
+        } in self.handles
+        {
+            drop(tx);
+            if let Err(e) = task.await {
+                warn!("sink {name}: task failed: {e}");
+            }
+            if dropped > 0 {
+                warn!("sink {name}: {dropped} snapshots dropped in total");
+            }
+        }
+    }
+}
+
+async fn drive_sink<S: Sink>(
+    name: String,
+    mut sink: S,
+    mut rx: mpsc::Receiver<Arc<Snapshot>>,
+    timeout: Duration,
+) {
+    while let Some(snapshot) = rx.recv().await {
+        match tokio::time::timeout(timeout, sink.export(&snapshot)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("sink {name}: export failed: {e:#}"),
+            Err(_) => warn!("sink {name}: export timed out after {timeout:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::SnapshotStats;
+
+    /// Sink keeping the `events` of the snapshots it exported.
+    #[derive(Clone, Default)]
+    struct Memory(Arc<Mutex<Vec<u64>>>);
+
+    impl Memory {
+        fn exported(&self) -> Vec<u64> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Sink for Memory {
+        async fn export(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(snapshot.stats.events);
+            Ok(())
+        }
+    }
+
+    struct Panicking;
+
+    impl Sink for Panicking {
+        async fn export(&mut self, _snapshot: &Snapshot) -> anyhow::Result<()> {
+            panic!("export failed");
+        }
+    }
+
+    fn snapshot(events: u64) -> Arc<Snapshot> {
+        Arc::new(Snapshot {
+            start: Timestamp::now(),
+            end: Timestamp::now(),
+            histograms: HashMap::new(),
+            labels: HashMap::new(),
+            stats: SnapshotStats {
+                events,
+                ..SnapshotStats::default()
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let (a, b) = (Memory::default(), Memory::default());
+        let mut sinks = Sinks::default();
+        sinks.add("a".to_owned(), a.clone(), SinkOptions::default());
+        sinks.add("b".to_owned(), b.clone(), SinkOptions::default());
+
+        for events in 1..=3 {
+            sinks.send(&snapshot(events));
+            tokio::task::yield_now().await;
+        }
+        sinks.close().await;
+
+        assert_eq!(a.exported(), [1, 2, 3]);
+        assert_eq!(b.exported(), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn full_queue() {
+        let memory = Memory::default();
+        let options = SinkOptions {
+            queue_capacity: 1,
+            ..SinkOptions::default()
+        };
+        let mut sinks = Sinks::default();
+        sinks.add("memory".to_owned(), memory.clone(), options);
+
+        // the export task does not run before the test yields
+        for events in 1..=3 {
+            sinks.send(&snapshot(events));
+        }
+        assert_eq!(sinks.handles[0].dropped, 2);
+        sinks.close().await;
+
+        assert_eq!(memory.exported(), [1]);
+    }
+
+    #[tokio::test]
+    async fn closed_sink() {
+        let memory = Memory::default();
+        let mut sinks = Sinks::default();
+        sinks.add("panicking".to_owned(), Panicking, SinkOptions::default());
+        sinks.add("memory".to_owned(), memory.clone(), SinkOptions::default());
+
+        sinks.send(&snapshot(1));
+        while !sinks.handles[0].task.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        for events in 2..=3 {
+            sinks.send(&snapshot(events));
+        }
+        assert!(sinks.handles[0].closed);
+        // only the snapshot sent when the task was found to have exited
+        assert_eq!(sinks.handles[0].dropped, 1);
+        assert_eq!(sinks.handles[1].dropped, 0);
+        sinks.close().await;
+
+        assert_eq!(memory.exported(), [1, 2, 3]);
+    }
+}
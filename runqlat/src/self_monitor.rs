@@ -0,0 +1,169 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc, time::Duration};
+
+use log::warn;
+use runqlat_common::{Histogram, MAX_SLOTS};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{Profiler, histogram, snapshot::Ticker};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SelfMonitorOptions {
+    /// How often histograms are drained from the eBPF maps.
+    pub interval: Duration,
+    /// Also record a histogram per thread of the current process.
+    pub per_thread: bool,
+}
+
+impl Default for SelfMonitorOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            per_thread: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ThreadLatency {
+    /// Thread name from `/proc/<pid>/task/<tid>/comm`, empty if it could not be read.
+    pub name: String,
+    /// Latencies observed during the last interval.
+    pub interval: Histogram,
+    /// Latencies observed since the monitor started.
+    pub total: Histogram,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfSnapshot {
+    pub pid: u32,
+    /// Latencies observed during the last interval.
+    pub interval: Histogram,
+    /// Latencies observed since the monitor started.
+    pub total: Histogram,
+    /// tid (thread id) -> latencies of the thread. Empty unless `per_thread` is set.
+    pub threads: HashMap<u32, ThreadLatency>,
+}
+
+impl SelfSnapshot {
+    /// Estimated `q`-quantile in us of all latencies observed since the monitor started.
+    pub fn percentile(&self, q: f64) -> Option<u64> {
+        histogram::percentile(&self.total, q)
+    }
+}
+
+/// Run queue latency monitor of the current process, see [`self_monitor`].
+///
+/// A background task drains the eBPF maps every interval; reading snapshots never touches the
+/// kernel. The task stops when the monitor is dropped.
+pub struct SelfMonitor {
+    snapshots: watch::Receiver<Arc<SelfSnapshot>>,
+    task: JoinHandle<()>,
+}
+
+/// Loads the eBPF program and starts monitoring the current process with default options.
+///
+/// Must be called within a tokio runtime.
+pub fn self_monitor() -> anyhow::Result<SelfMonitor> {
+    SelfMonitor::start(SelfMonitorOptions::default())
+}
+
+impl SelfMonitor {
+    /// Loads the eBPF program and starts monitoring the current process.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn start(options: SelfMonitorOptions) -> anyhow::Result<Self> {
+        let mut profiler = Profiler::try_new()?;
+        let pid = std::process::id();
+        if options.per_thread {
+            profiler.track_threads(&[pid])?;
+        } else {
+            profiler.insert_pids(&[pid])?;
+        }
+
+        let (tx, snapshots) = watch::channel(Arc::new(SelfSnapshot {
+            pid,
+            interval: [0; MAX_SLOTS],
+            total: [0; MAX_SLOTS],
+            threads: HashMap::new(),
+        }));
+        let task = tokio::spawn(refresh(profiler, options, tx));
+
+        Ok(Self { snapshots, task })
+    }
+
+    /// Latest snapshot, updated once per interval.
+    pub fn snapshot(&self) -> Arc<SelfSnapshot> {
+        self.snapshots.borrow().clone()
+    }
+
+    /// Estimated `q`-quantile in us of all latencies observed since the monitor started.
+    pub fn percentile(&self, q: f64) -> Option<u64> {
+        self.snapshot().percentile(q)
+    }
+
+    /// Receiver notified every time a new snapshot is published, e.g. to log or alert on it.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<SelfSnapshot>> {
+        self.snapshots.clone()
+    }
+}
+
+impl Drop for SelfMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn refresh(
+    mut profiler: Profiler,
+    options: SelfMonitorOptions,
+    tx: watch::Sender<Arc<SelfSnapshot>>,
+) {
+    let mut ticker = Ticker::new(options.interval);
+    loop {
+        ticker.tick().await;
+        let mut snapshot = SelfSnapshot::clone(&tx.borrow());
+        match update(&mut profiler, &mut snapshot, options.per_thread) {
+            Ok(()) => {
+                tx.send_replace(Arc::new(snapshot));
+            }
+            Err(e) => warn!("self monitor: {e:#}"),
+        }
+    }
+}
+
+fn update(
+    profiler: &mut Profiler,
+    snapshot: &mut SelfSnapshot,
+    per_thread: bool,
+) -> anyhow::Result<()> {
+    let histograms = profiler.drain_histograms()?;
+    snapshot.interval = histograms
+        .get(&snapshot.pid)
+        .copied()
+        .unwrap_or([0; MAX_SLOTS]);
+    histogram::merge(&mut snapshot.total, &snapshot.interval);
+
+    if !per_thread {
+        return Ok(());
+    }
+
+    let pid = snapshot.pid;
+    for thread in snapshot.threads.values_mut() {
+        thread.interval = [0; MAX_SLOTS];
+    }
+    for (tid, hist) in profiler.drain_thread_histograms()? {
+        let thread = snapshot.threads.entry(tid).or_default();
+        // Threads are often renamed after they are spawned, so refresh the name while it lives.
+        if let Ok(name) = fs::read_to_string(format!("/proc/{pid}/task/{tid}/comm")) {
+            thread.name = name.trim_end().to_owned();
+        }
+        thread.interval = hist;
+        histogram::merge(&mut thread.total, &hist);
+    }
+    snapshot.threads.retain(|tid, thread| {
+        histogram::count(&thread.interval) > 0
+            || Path::new(&format!("/proc/{pid}/task/{tid}")).exists()
+    });
+
+    Ok(())
+}
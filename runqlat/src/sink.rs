@@ -0,0 +1,11 @@
+use std::future::Future;
+
+use crate::Snapshot;
+
+/// Destination for interval snapshots produced by a [`Pipeline`](crate::Pipeline).
+///
+/// Each sink is driven from its own task, so `export` may take as long as it needs without
+/// delaying collection or other sinks; the pipeline enforces a per-call timeout.
+pub trait Sink: Send + 'static {
+    fn export(&mut self, snapshot: &Snapshot) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
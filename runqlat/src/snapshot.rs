@@ -0,0 +1,221 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_core::Stream;
+use runqlat_common::{Histogram, MAX_SLOTS};
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::{ProcInfo, Profiler, histogram, targets::Labels};
+
+/// Point in time captured on both clocks.
+///
+/// Use `monotonic` for interval math (durations, rates) and `wall` only to label data, since the
+/// wall clock may jump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub monotonic: Instant,
+    pub wall: SystemTime,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self {
+            monotonic: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotStats {
+    /// Number of latency samples across all histograms.
+    pub events: u64,
+    /// Number of processes with at least one sample.
+    pub processes: usize,
+    /// Number of scheduled ticks skipped since the previous snapshot because the consumer fell
+    /// behind. Their samples are not lost; they are included in this snapshot.
+    pub missed_ticks: u64,
+    /// Number of histograms dropped because their pid was reused by another process during the
+    /// interval.
+    pub recycled: usize,
+}
+
+/// Histograms drained from the eBPF maps over one collection interval.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Time at which the interval started, i.e. the previous drain.
+    pub start: Timestamp,
+    /// Time at which the histograms were drained.
+    pub end: Timestamp,
+    /// tgid (process id) -> histogram of run queue latencies accumulated during the interval.
+    pub histograms: HashMap<u32, Histogram>,
+    /// tgid (process id) -> labels of the target, for histograms of labeled targets.
+    pub labels: HashMap<u32, Labels>,
+    pub stats: SnapshotStats,
+}
+
+impl Snapshot {
+    /// Drains `profiler` and builds the snapshot of the interval that began at `start`, after
+    /// `missed_ticks` ticks were skipped, see [`Ticker::poll_tick`].
+    pub(crate) fn collect(
+        profiler: &mut Profiler,
+        start: Timestamp,
+        missed_ticks: u64,
+    ) -> anyhow::Result<Self> {
+        let mut histograms = profiler.drain_histograms()?;
+        let end = Timestamp::now();
+        // Samples of a reused pid may belong to either process, so they are dropped.
+        let recycled = profiler.untrack_recycled(histograms.keys(), &ProcInfo::default())?;
+        for pid in &recycled {
+            histograms.remove(pid);
+        }
+        let labels = profiler.take_labels(histograms.keys());
+
+        let stats = SnapshotStats {
+            events: histograms.values().map(histogram::count).sum(),
+            processes: histograms.len(),
+            missed_ticks,
+            recycled: recycled.len(),
+        };
+
+        Ok(Self {
+            start,
+            end,
+            histograms,
+            labels,
+            stats,
+        })
+    }
+
+    /// Histograms merged by the value of the label `key`, e.g. `uid` to compare users. Histograms
+    /// of processes without the label are left out.
+    pub fn aggregate(&self, key: &str) -> BTreeMap<String, Histogram> {
+        let mut aggregated = BTreeMap::<String, Histogram>::new();
+        for (pid, hist) in &self.histograms {
+            let Some(value) = self.labels.get(pid).and_then(|labels| labels.get(key)) else {
+                continue;
+            };
+            histogram::merge(
+                aggregated.entry(value.clone()).or_insert([0; MAX_SLOTS]),
+                hist,
+            );
+        }
+        aggregated
+    }
+
+    /// Actual length of the interval, measured on the monotonic clock.
+    pub fn duration(&self) -> Duration {
+        self.end
+            .monotonic
+            .saturating_duration_since(self.start.monotonic)
+    }
+}
+
+/// Shortest period of [`ticker`]; shorter ones, including zero, are raised to it.
+pub(crate) const MIN_PERIOD: Duration = Duration::from_millis(1);
+
+/// Ticker firing every `period`, at least [`MIN_PERIOD`], starting one period from now.
+///
+/// A tick missed while the consumer was busy fires as soon as possible, and the ticks that
+/// passed since are skipped, so ticks stay at multiples of `period` from the start.
+pub(crate) struct Ticker {
+    interval: Interval,
+    period: Duration,
+}
+
+impl Ticker {
+    pub(crate) fn new(period: Duration) -> Self {
+        let period = period.max(MIN_PERIOD);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        Self { interval, period }
+    }
+
+    /// Waits for the next tick. Returns the number of ticks skipped because it fired late.
+    pub(crate) fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<u64> {
+        let tick = ready!(self.interval.poll_tick(cx));
+        // The interval skips to the first multiple of the period after now.
+        let late = tokio::time::Instant::now().saturating_duration_since(tick);
+        let skipped = late.as_nanos() / self.period.as_nanos();
+        Poll::Ready(u64::try_from(skipped).unwrap_or(u64::MAX))
+    }
+
+    /// See [`Ticker::poll_tick`].
+    pub(crate) async fn tick(&mut self) -> u64 {
+        std::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+}
+
+/// Stream of snapshots returned by [`Profiler::stream`].
+pub struct SnapshotStream<'a> {
+    profiler: &'a mut Profiler,
+    ticker: Ticker,
+    start: Timestamp,
+}
+
+impl<'a> SnapshotStream<'a> {
+    pub(crate) fn new(profiler: &'a mut Profiler, period: Duration) -> Self {
+        Self {
+            profiler,
+            ticker: Ticker::new(period),
+            start: Timestamp::now(),
+        }
+    }
+}
+
+impl Stream for SnapshotStream<'_> {
+    type Item = anyhow::Result<Snapshot>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let missed_ticks = ready!(this.ticker.poll_tick(cx));
+        let snapshot = Snapshot::collect(this.profiler, this.start, missed_ticks);
+        if let Ok(snapshot) = &snapshot {
+            this.start = snapshot.end;
+        }
+        Poll::Ready(Some(snapshot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_consumer() {
+        let start = Instant::now();
+        let period = Duration::from_secs(1);
+        let mut ticker = Ticker::new(period);
+
+        assert_eq!(ticker.tick().await, 0);
+        assert_eq!(start.elapsed(), period);
+
+        // ticks 2, 3 and 4 pass while the consumer is busy: 2 fires late, 3 and 4 are skipped
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        assert_eq!(ticker.tick().await, 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(4500));
+
+        assert_eq!(ticker.tick().await, 0);
+        assert_eq!(start.elapsed(), 5 * period);
+
+        // late by exactly one period: the tick at 7s is skipped
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(ticker.tick().await, 1);
+        assert_eq!(ticker.tick().await, 0);
+        assert_eq!(start.elapsed(), 8 * period);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_period() {
+        let start = Instant::now();
+        let mut ticker = Ticker::new(Duration::ZERO);
+        assert_eq!(ticker.tick().await, 0);
+        assert_eq!(start.elapsed(), MIN_PERIOD);
+    }
+}
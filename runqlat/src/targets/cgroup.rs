@@ -0,0 +1,389 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+
+use crate::targets::{Labels, Target, TargetSource, blocking};
+
+/// Mount point of the unified (v2) cgroup hierarchy.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// `.scope` prefixes used by the systemd cgroup driver, and the runtime they belong to.
+const SCOPE_PREFIXES: [(&str, &str); 4] = [
+    ("cri-containerd-", "containerd"),
+    ("docker-", "docker"),
+    ("crio-", "cri-o"),
+    ("libpod-", "podman"),
+];
+
+/// Container found in the cgroup hierarchy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerCgroup {
+    /// Directory of the container's cgroup.
+    pub path: PathBuf,
+    /// Full (64 hex characters) container id.
+    pub id: String,
+    /// Runtime that created the container, when the cgroup name tells.
+    pub runtime: Option<&'static str>,
+    /// UID of the Kubernetes pod the container belongs to.
+    pub pod_uid: Option<String>,
+}
+
+/// Parses the container id and runtime from the name of a cgroup directory.
+///
+/// Understands the systemd driver (`cri-containerd-<id>.scope`, `docker-<id>.scope`, ...) and
+/// the cgroupfs driver (`<id>`) layouts.
+pub fn parse_container_id(name: &str) -> Option<(&str, Option<&'static str>)> {
+    if let Some(scope) = name.strip_suffix(".scope") {
+        return SCOPE_PREFIXES.iter().find_map(|&(prefix, runtime)| {
+            let id = scope.strip_prefix(prefix)?;
+            is_container_id(id).then_some((id, Some(runtime)))
+        });
+    }
+    is_container_id(name).then_some((name, None))
+}
+
+/// Parses the pod UID from the name of a Kubernetes pod cgroup directory, e.g.
+/// `kubepods-burstable-pod1b2c..._...slice` (systemd driver) or `pod1b2c...-...` (cgroupfs
+/// driver).
+pub fn parse_pod_uid(name: &str) -> Option<String> {
+    let uid = match name.strip_suffix(".slice") {
+        // The systemd driver escapes dashes in the UID as underscores.
+        Some(slice) => slice.rsplit_once("-pod")?.1.replace('_', "-"),
+        None => name.strip_prefix("pod")?.to_owned(),
+    };
+    (uid.len() == 36 && uid.chars().all(|c| c.is_ascii_hexdigit() || c == '-')).then_some(uid)
+}
+
+fn is_container_id(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Finds all containers in the cgroup hierarchy mounted at `root`.
+///
+/// Cgroups nested in a container's cgroup are considered part of that container.
+pub fn find_containers(root: &Path) -> io::Result<Vec<ContainerCgroup>> {
+    let mut containers = Vec::new();
+    walk_containers(root, None, &mut containers)?;
+    Ok(containers)
+}
+
+fn walk_containers(
+    dir: &Path,
+    pod_uid: Option<&str>,
+    containers: &mut Vec<ContainerCgroup>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+
+        if let Some((id, runtime)) = parse_container_id(&name) {
+            containers.push(ContainerCgroup {
+                id: id.to_owned(),
+                runtime,
+                pod_uid: pod_uid.map(str::to_owned),
+                path,
+            });
+            continue;
+        }
+
+        let pod_uid = parse_pod_uid(&name).or_else(|| pod_uid.map(str::to_owned));
+        match walk_containers(&path, pod_uid.as_deref(), containers) {
+            // Cgroups may be removed while walking the hierarchy.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// PIDs of all processes in the cgroup at `dir` and its descendants.
+///
+/// Cgroups removed while reading are skipped.
+pub fn read_procs(dir: &Path) -> io::Result<Vec<u32>> {
+    let mut pids = Vec::new();
+    read_procs_into(dir, &mut pids)?;
+    Ok(pids)
+}
+
+fn read_procs_into(dir: &Path, pids: &mut Vec<u32>) -> io::Result<()> {
+    let procs = match fs::read_to_string(dir.join("cgroup.procs")) {
+        Ok(procs) => procs,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    pids.extend(
+        procs
+            .lines()
+            .filter_map(|line| line.trim().parse::<u32>().ok()),
+    );
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            read_procs_into(&entry.path(), pids)?;
+        }
+    }
+    Ok(())
+}
+
+/// Cgroup of process `pid` relative to the root of the cgroup v2 hierarchy, read from the
+/// procfs mounted at `proc_root`.
+pub fn process_cgroup(proc_root: &Path, pid: u32) -> io::Result<PathBuf> {
+    let cgroup = fs::read_to_string(proc_root.join(pid.to_string()).join("cgroup"))?;
+    cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| PathBuf::from(path.trim_start_matches('/')))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "process not in cgroup v2"))
+}
+
+/// PIDs of all processes in the cgroup of process `pid` and its descendants, e.g. every process
+/// of the container `pid` runs in.
+pub fn cgroup_procs(proc_root: &Path, cgroup_root: &Path, pid: u32) -> io::Result<Vec<u32>> {
+    read_procs(&cgroup_root.join(process_cgroup(proc_root, pid)?))
+}
+
+/// Tracks the processes of every container found in the cgroup v2 hierarchy.
+///
+/// Targets are labeled with `container_id`, and `runtime` and `pod_uid` when known.
+#[derive(Debug, Clone)]
+pub struct CgroupSource {
+    root: PathBuf,
+}
+
+impl Default for CgroupSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CgroupSource {
+    pub fn new() -> Self {
+        Self::with_root(CGROUP_ROOT)
+    }
+
+    /// Walks the cgroup hierarchy mounted at `root` instead of [`CGROUP_ROOT`].
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl TargetSource for CgroupSource {
+    fn name(&self) -> &str {
+        "cgroup"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        let root = self.root.clone();
+        blocking(move || container_targets(&root)).await
+    }
+}
+
+fn container_targets(root: &Path) -> anyhow::Result<Vec<Target>> {
+    let containers =
+        find_containers(root).with_context(|| format!("failed to walk {}", root.display()))?;
+
+    let mut targets = Vec::new();
+    for container in containers {
+        let pids = read_procs(&container.path)
+            .with_context(|| format!("failed to read {}", container.path.display()))?;
+
+        let mut labels = Labels::new();
+        labels.insert("container_id".to_owned(), container.id);
+        if let Some(runtime) = container.runtime {
+            labels.insert("runtime".to_owned(), runtime.to_owned());
+        }
+        if let Some(pod_uid) = container.pod_uid {
+            labels.insert("pod_uid".to_owned(), pod_uid);
+        }
+
+        targets.extend(pids.into_iter().map(|pid| Target {
+            pid,
+            labels: labels.clone(),
+        }));
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const OTHER_ID: &str = "fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210";
+    const POD_UID: &str = "1b2c3d4e-5f60-7182-93a4-b5c6d7e8f901";
+
+    fn add_cgroup(root: &Path, path: &str, pids: &[u32]) {
+        let dir = root.join(path);
+        fs::create_dir_all(&dir).unwrap();
+        let procs: String = pids.iter().map(|pid| format!("{pid}\n")).collect();
+        fs::write(dir.join("cgroup.procs"), procs).unwrap();
+    }
+
+    #[test]
+    fn container_id() {
+        assert_eq!(
+            parse_container_id(&format!("cri-containerd-{ID}.scope")),
+            Some((ID, Some("containerd")))
+        );
+        assert_eq!(
+            parse_container_id(&format!("docker-{ID}.scope")),
+            Some((ID, Some("docker")))
+        );
+        assert_eq!(
+            parse_container_id(&format!("crio-{ID}.scope")),
+            Some((ID, Some("cri-o")))
+        );
+        assert_eq!(
+            parse_container_id(&format!("libpod-{ID}.scope")),
+            Some((ID, Some("podman")))
+        );
+        assert_eq!(parse_container_id(ID), Some((ID, None)));
+
+        assert_eq!(parse_container_id("session-1.scope"), None);
+        assert_eq!(parse_container_id(&format!("unknown-{ID}.scope")), None);
+        assert_eq!(
+            parse_container_id(&format!("docker-{}.scope", &ID[1..])),
+            None
+        );
+        assert_eq!(parse_container_id(&ID.replace('a', "g")), None);
+    }
+
+    #[test]
+    fn pod_uid() {
+        let escaped = POD_UID.replace('-', "_");
+        assert_eq!(
+            parse_pod_uid(&format!("kubepods-burstable-pod{escaped}.slice")).as_deref(),
+            Some(POD_UID)
+        );
+        assert_eq!(
+            parse_pod_uid(&format!("kubepods-pod{escaped}.slice")).as_deref(),
+            Some(POD_UID)
+        );
+        assert_eq!(
+            parse_pod_uid(&format!("pod{POD_UID}")).as_deref(),
+            Some(POD_UID)
+        );
+
+        assert_eq!(parse_pod_uid("kubepods-burstable.slice"), None);
+        assert_eq!(parse_pod_uid(&format!("pod{}", &POD_UID[1..])), None);
+        assert_eq!(parse_pod_uid("podman.slice"), None);
+    }
+
+    #[tokio::test]
+    async fn source() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        add_cgroup(root, "user.slice", &[1]);
+        add_cgroup(root, &format!("system.slice/docker-{ID}.scope"), &[10, 11]);
+        add_cgroup(root, &format!("system.slice/docker-{ID}.scope/init"), &[12]);
+        let pod = format!(
+            "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice",
+            POD_UID.replace('-', "_")
+        );
+        add_cgroup(root, &pod, &[]);
+        add_cgroup(
+            root,
+            &format!("{pod}/cri-containerd-{OTHER_ID}.scope"),
+            &[20],
+        );
+
+        let mut containers = find_containers(root).unwrap();
+        containers.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            containers,
+            [
+                ContainerCgroup {
+                    path: root.join(format!("system.slice/docker-{ID}.scope")),
+                    id: ID.to_owned(),
+                    runtime: Some("docker"),
+                    pod_uid: None,
+                },
+                ContainerCgroup {
+                    path: root.join(format!("{pod}/cri-containerd-{OTHER_ID}.scope")),
+                    id: OTHER_ID.to_owned(),
+                    runtime: Some("containerd"),
+                    pod_uid: Some(POD_UID.to_owned()),
+                },
+            ]
+        );
+
+        let mut targets = CgroupSource::with_root(root).targets().await.unwrap();
+        targets.sort_by_key(|target| target.pid);
+        let docker = Labels::from([
+            ("container_id".to_owned(), ID.to_owned()),
+            ("runtime".to_owned(), "docker".to_owned()),
+        ]);
+        let containerd = Labels::from([
+            ("container_id".to_owned(), OTHER_ID.to_owned()),
+            ("runtime".to_owned(), "containerd".to_owned()),
+            ("pod_uid".to_owned(), POD_UID.to_owned()),
+        ]);
+        assert_eq!(
+            targets,
+            [
+                Target {
+                    pid: 10,
+                    labels: docker.clone()
+                },
+                Target {
+                    pid: 11,
+                    labels: docker.clone()
+                },
+                Target {
+                    pid: 12,
+                    labels: docker
+                },
+                Target {
+                    pid: 20,
+                    labels: containerd
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn procs_of_process_cgroup() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let cgroup_root = tempfile::tempdir().unwrap();
+        fs::create_dir(proc_root.path().join("42")).unwrap();
+        fs::write(
+            proc_root.path().join("42/cgroup"),
+            "0::/system.slice/nginx.service\n",
+        )
+        .unwrap();
+        add_cgroup(cgroup_root.path(), "system.slice/nginx.service", &[42, 43]);
+        add_cgroup(
+            cgroup_root.path(),
+            "system.slice/nginx.service/worker",
+            &[44],
+        );
+
+        assert_eq!(
+            process_cgroup(proc_root.path(), 42).unwrap(),
+            Path::new("system.slice/nginx.service")
+        );
+        let mut pids = cgroup_procs(proc_root.path(), cgroup_root.path(), 42).unwrap();
+        pids.sort_unstable();
+        assert_eq!(pids, [42, 43, 44]);
+        assert!(
+            read_procs(&cgroup_root.path().join("missing"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
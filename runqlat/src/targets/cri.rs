@@ -0,0 +1,658 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, anyhow};
+use log::warn;
+use tonic::{
+    Request,
+    client::Grpc,
+    codegen::http::uri::PathAndQuery,
+    transport::{Channel, Endpoint},
+};
+use tonic_prost::ProstCodec;
+
+use crate::targets::{
+    Labels, Target, TargetSource, blocking,
+    cgroup::{self, CGROUP_ROOT},
+};
+
+/// Default CRI socket of containerd.
+pub const CONTAINERD_SOCKET: &str = "/run/containerd/containerd.sock";
+/// Default CRI socket of CRI-O.
+pub const CRIO_SOCKET: &str = "/var/run/crio/crio.sock";
+
+/// Subset of the `runtime.v1` CRI API used for discovery.
+///
+/// https://github.com/kubernetes/cri-api/blob/master/pkg/apis/runtime/v1/api.proto
+mod proto {
+    use std::collections::HashMap;
+
+    pub const CONTAINER_RUNNING: i32 = 1;
+    pub const SANDBOX_READY: i32 = 0;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListContainersRequest {
+        #[prost(message, optional, tag = "1")]
+        pub filter: Option<ContainerFilter>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerFilter {
+        #[prost(message, optional, tag = "2")]
+        pub state: Option<ContainerStateValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerStateValue {
+        #[prost(int32, tag = "1")]
+        pub state: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListContainersResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub containers: Vec<Container>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Container {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub pod_sandbox_id: String,
+        #[prost(message, optional, tag = "3")]
+        pub metadata: Option<ContainerMetadata>,
+        #[prost(message, optional, tag = "4")]
+        pub image: Option<ImageSpec>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerMetadata {
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ImageSpec {
+        #[prost(string, tag = "1")]
+        pub image: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerStatusRequest {
+        #[prost(string, tag = "1")]
+        pub container_id: String,
+        #[prost(bool, tag = "2")]
+        pub verbose: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ContainerStatusResponse {
+        /// Runtime specific details, only set for verbose requests.
+        #[prost(map = "string, string", tag = "2")]
+        pub info: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListPodSandboxRequest {
+        #[prost(message, optional, tag = "1")]
+        pub filter: Option<PodSandboxFilter>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PodSandboxFilter {
+        #[prost(message, optional, tag = "2")]
+        pub state: Option<PodSandboxStateValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PodSandboxStateValue {
+        #[prost(int32, tag = "1")]
+        pub state: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListPodSandboxResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub items: Vec<PodSandbox>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PodSandbox {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(message, optional, tag = "2")]
+        pub metadata: Option<PodSandboxMetadata>,
+        #[prost(map = "string, string", tag = "5")]
+        pub labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PodSandboxMetadata {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub uid: String,
+        #[prost(string, tag = "3")]
+        pub namespace: String,
+    }
+}
+
+/// Running container reported by the CRI runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CriContainer {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    /// PID of the container's init process.
+    pub pid: u32,
+    pub pod_name: Option<String>,
+    pub pod_namespace: Option<String>,
+    pub pod_uid: Option<String>,
+    pub pod_labels: BTreeMap<String, String>,
+}
+
+/// CRI `RuntimeService` client over a unix socket.
+pub struct CriClient {
+    grpc: Grpc<Channel>,
+    /// container id -> init PID, which does not change during the container's life.
+    pids: HashMap<String, u32>,
+}
+
+impl CriClient {
+    pub async fn connect(socket: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let socket = socket.as_ref();
+        let channel = Endpoint::from_shared(format!("unix://{}", socket.display()))?
+            .connect()
+            .await
+            .with_context(|| format!("failed to connect to {}", socket.display()))?;
+
+        Ok(Self {
+            grpc: Grpc::new(channel),
+            pids: HashMap::new(),
+        })
+    }
+
+    async fn unary<Req, Resp>(&mut self, path: &'static str, request: Req) -> anyhow::Result<Resp>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Resp: prost::Message + Default + Send + Sync + 'static,
+    {
+        self.grpc
+            .ready()
+            .await
+            .map_err(|e| anyhow!("CRI service not ready: {e}"))?;
+        let response = self
+            .grpc
+            .unary(
+                Request::new(request),
+                PathAndQuery::from_static(path),
+                ProstCodec::default(),
+            )
+            .await
+            .with_context(|| format!("{path} failed"))?;
+        Ok(response.into_inner())
+    }
+
+    /// Running containers along with their pods.
+    pub async fn containers(&mut self) -> anyhow::Result<Vec<CriContainer>> {
+        let proto::ListContainersResponse { containers } = self
+            .unary(
+                "/runtime.v1.RuntimeService/ListContainers",
+                proto::ListContainersRequest {
+                    filter: Some(proto::ContainerFilter {
+                        state: Some(proto::ContainerStateValue {
+                            state: proto::CONTAINER_RUNNING,
+                        }),
+                    }),
+                },
+            )
+            .await?;
+        let proto::ListPodSandboxResponse { items } = self
+            .unary(
+                "/runtime.v1.RuntimeService/ListPodSandbox",
+                proto::ListPodSandboxRequest {
+                    filter: Some(proto::PodSandboxFilter {
+                        state: Some(proto::PodSandboxStateValue {
+                            state: proto::SANDBOX_READY,
+                        }),
+                    }),
+                },
+            )
+            .await?;
+        let pods: HashMap<String, proto::PodSandbox> =
+            items.into_iter().map(|pod| (pod.id.clone(), pod)).collect();
+
+        self.pids
+            .retain(|id, _| containers.iter().any(|container| &container.id == id));
+
+        let mut out = Vec::with_capacity(containers.len());
+        for container in containers {
+            let pid = match self.pids.get(&container.id) {
+                Some(&pid) => pid,
+                None => match self.container_pid(&container.id).await {
+                    Ok(pid) => {
+                        self.pids.insert(container.id.clone(), pid);
+                        pid
+                    }
+                    // The container may have stopped since it was listed.
+                    Err(e) => {
+                        warn!("container {}: {e:#}", container.id);
+                        continue;
+                    }
+                },
+            };
+
+            let pod = pods.get(&container.pod_sandbox_id);
+            let pod_metadata = pod.and_then(|pod| pod.metadata.as_ref());
+            out.push(CriContainer {
+                name: container.metadata.map(|m| m.name).unwrap_or_default(),
+                image: container.image.map(|i| i.image).unwrap_or_default(),
+                pid,
+                pod_name: pod_metadata.map(|m| m.name.clone()),
+                pod_namespace: pod_metadata.map(|m| m.namespace.clone()),
+                pod_uid: pod_metadata.map(|m| m.uid.clone()),
+                pod_labels: pod
+                    .map(|pod| pod.labels.clone().into_iter().collect())
+                    .unwrap_or_default(),
+                id: container.id,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Reads the init PID from the runtime specific verbose status, which both containerd and
+    /// CRI-O report as `{"pid": ...}` in the `info` entry.
+    async fn container_pid(&mut self, id: &str) -> anyhow::Result<u32> {
+        let proto::ContainerStatusResponse { info } = self
+            .unary(
+                "/runtime.v1.RuntimeService/ContainerStatus",
+                proto::ContainerStatusRequest {
+                    container_id: id.to_owned(),
+                    verbose: true,
+                },
+            )
+            .await?;
+        let info = info
+            .get("info")
+            .ok_or_else(|| anyhow!("no verbose info in container status"))?;
+        let info: serde_json::Value =
+            serde_json::from_str(info).context("invalid verbose container info")?;
+        info["pid"]
+            .as_u64()
+            .and_then(|pid| u32::try_from(pid).ok())
+            .filter(|&pid| pid != 0)
+            .ok_or_else(|| anyhow!("no pid in verbose container info"))
+    }
+}
+
+/// Tracks the containers of a CRI runtime (containerd, CRI-O).
+///
+/// Targets are labeled with `container`, `container_id`, `image`, and `pod`, `namespace` and
+/// `pod_uid` for containers in a pod. By default every process in the container's cgroup is
+/// tracked, not only its init process.
+pub struct CriSource {
+    socket: PathBuf,
+    client: Option<CriClient>,
+    all_processes: bool,
+    pod_labels: bool,
+    proc_root: PathBuf,
+    cgroup_root: PathBuf,
+}
+
+impl CriSource {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+            client: None,
+            all_processes: true,
+            pod_labels: false,
+            proc_root: PathBuf::from("/proc"),
+            cgroup_root: PathBuf::from(CGROUP_ROOT),
+        }
+    }
+
+    /// Tracks only the init process of each container.
+    pub fn init_only(mut self) -> Self {
+        self.all_processes = false;
+        self
+    }
+
+    /// Also labels targets with the labels of their pod, as `pod_label_<key>` with characters
+    /// other than ASCII alphanumerics replaced by `_`.
+    pub fn with_pod_labels(mut self) -> Self {
+        self.pod_labels = true;
+        self
+    }
+
+    /// Procfs and cgroup v2 mounts used to find the processes of a container.
+    pub fn with_roots(
+        mut self,
+        proc_root: impl Into<PathBuf>,
+        cgroup_root: impl Into<PathBuf>,
+    ) -> Self {
+        self.proc_root = proc_root.into();
+        self.cgroup_root = cgroup_root.into();
+        self
+    }
+
+    fn labels(&self, container: &CriContainer) -> Labels {
+        let mut labels = Labels::new();
+        labels.insert("container".to_owned(), container.name.clone());
+        labels.insert("container_id".to_owned(), container.id.clone());
+        labels.insert("image".to_owned(), container.image.clone());
+        if let Some(pod) = &container.pod_name {
+            labels.insert("pod".to_owned(), pod.clone());
+        }
+        if let Some(namespace) = &container.pod_namespace {
+            labels.insert("namespace".to_owned(), namespace.clone());
+        }
+        if let Some(uid) = &container.pod_uid {
+            labels.insert("pod_uid".to_owned(), uid.clone());
+        }
+        if self.pod_labels {
+            for (key, value) in &container.pod_labels {
+                let key: String = key
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                labels.insert(format!("pod_label_{key}"), value.clone());
+            }
+        }
+        labels
+    }
+
+    async fn pids(&self, container: &CriContainer) -> Vec<u32> {
+        if !self.all_processes {
+            return vec![container.pid];
+        }
+        let (proc_root, cgroup_root) = (self.proc_root.clone(), self.cgroup_root.clone());
+        let pid = container.pid;
+        let procs = blocking(move || Ok(cgroup::cgroup_procs(&proc_root, &cgroup_root, pid)?));
+        match procs.await {
+            Ok(pids) if !pids.is_empty() => pids,
+            Ok(_) => vec![container.pid],
+            Err(e) => {
+                warn!("container {}: failed to read cgroup: {e:#}", container.id);
+                vec![container.pid]
+            }
+        }
+    }
+}
+
+impl TargetSource for CriSource {
+    fn name(&self) -> &str {
+        "cri"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        let client = match &mut self.client {
+            Some(client) => client,
+            client @ None => client.insert(CriClient::connect(&self.socket).await?),
+        };
+        let containers = match client.containers().await {
+            Ok(containers) => containers,
+            Err(e) => {
+                // Reconnect on the next poll, e.g. after the runtime restarted.
+                self.client = None;
+                return Err(e);
+            }
+        };
+
+        let mut targets = Vec::new();
+        for container in &containers {
+            let labels = self.labels(container);
+            targets.extend(self.pids(container).await.into_iter().map(|pid| Target {
+                pid,
+                labels: labels.clone(),
+            }));
+        }
+        Ok(targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        fs,
+        marker::PhantomData,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        task::{Context, Poll},
+    };
+
+    use tokio::net::UnixListener;
+    use tonic::{
+        Status,
+        body::Body,
+        codegen::{BoxFuture, Service, http, tokio_stream::wrappers::UnixListenerStream},
+        server::{Grpc, UnaryService},
+        transport::Server,
+    };
+
+    use super::*;
+
+    const POD_UID: &str = "1b2c3d4e-5f60-7182-93a4-b5c6d7e8f901";
+
+    /// CRI runtime with a running container in a pod and one whose status has no pid.
+    #[derive(Clone)]
+    struct MockRuntime {
+        status_calls: Arc<AtomicUsize>,
+    }
+
+    impl MockRuntime {
+        fn containers() -> Vec<proto::Container> {
+            ["web", "exited"]
+                .into_iter()
+                .map(|name| proto::Container {
+                    id: format!("{name}-id"),
+                    pod_sandbox_id: "pod-id".to_owned(),
+                    metadata: Some(proto::ContainerMetadata {
+                        name: name.to_owned(),
+                    }),
+                    image: Some(proto::ImageSpec {
+                        image: "nginx:1.27".to_owned(),
+                    }),
+                })
+                .collect()
+        }
+
+        fn pods() -> Vec<proto::PodSandbox> {
+            vec![proto::PodSandbox {
+                id: "pod-id".to_owned(),
+                metadata: Some(proto::PodSandboxMetadata {
+                    name: "web-0".to_owned(),
+                    uid: POD_UID.to_owned(),
+                    namespace: "default".to_owned(),
+                }),
+                labels: HashMap::from([("app.kubernetes.io/name".to_owned(), "web".to_owned())]),
+            }]
+        }
+
+        fn status(id: &str) -> Result<proto::ContainerStatusResponse, Status> {
+            let info = match id {
+                "web-id" => r#"{"pid": 4242, "sandboxID": "pod-id"}"#,
+                "exited-id" => r#"{"pid": 0}"#,
+                _ => return Err(Status::not_found(id)),
+            };
+            Ok(proto::ContainerStatusResponse {
+                info: HashMap::from([("info".to_owned(), info.to_owned())]),
+            })
+        }
+    }
+
+    impl Service<http::Request<Body>> for MockRuntime {
+        type Response = http::Response<Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Infallible>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            let status_calls = self.status_calls.clone();
+            Box::pin(async move {
+                let response = match request.uri().path() {
+                    "/runtime.v1.RuntimeService/ListContainers" => {
+                        unary(request, |request: proto::ListContainersRequest| {
+                            let state = request.filter.and_then(|filter| filter.state);
+                            assert_eq!(state.map(|s| s.state), Some(proto::CONTAINER_RUNNING));
+                            Ok(proto::ListContainersResponse {
+                                containers: Self::containers(),
+                            })
+                        })
+                        .await
+                    }
+                    "/runtime.v1.RuntimeService/ListPodSandbox" => {
+                        unary(request, |_: proto::ListPodSandboxRequest| {
+                            Ok(proto::ListPodSandboxResponse {
+                                items: Self::pods(),
+                            })
+                        })
+                        .await
+                    }
+                    "/runtime.v1.RuntimeService/ContainerStatus" => {
+                        unary(request, |request: proto::ContainerStatusRequest| {
+                            assert!(request.verbose);
+                            status_calls.fetch_add(1, Ordering::Relaxed);
+                            Self::status(&request.container_id)
+                        })
+                        .await
+                    }
+                    path => Status::unimplemented(path).into_http(),
+                };
+                Ok(response)
+            })
+        }
+    }
+
+    /// Unary gRPC handler calling a closure once.
+    struct Handler<F, Resp>(Option<F>, PhantomData<fn() -> Resp>);
+
+    impl<Req, Resp, F> UnaryService<Req> for Handler<F, Resp>
+    where
+        F: FnOnce(Req) -> Result<Resp, Status>,
+    {
+        type Response = Resp;
+        type Future = std::future::Ready<Result<tonic::Response<Resp>, Status>>;
+
+        fn call(&mut self, request: Request<Req>) -> Self::Future {
+            let handler = self.0.take().expect("unary handler called twice");
+            std::future::ready(handler(request.into_inner()).map(tonic::Response::new))
+        }
+    }
+
+    async fn unary<Req, Resp>(
+        request: http::Request<Body>,
+        handler: impl FnOnce(Req) -> Result<Resp, Status>,
+    ) -> http::Response<Body>
+    where
+        Req: prost::Message + Default + Send + 'static,
+        Resp: prost::Message + Send + 'static,
+    {
+        Grpc::new(ProstCodec::default())
+            .unary(Handler(Some(handler), PhantomData), request)
+            .await
+    }
+
+    /// Serves a [`MockRuntime`] on a socket in `dir`.
+    fn serve(dir: &Path) -> (PathBuf, Arc<AtomicUsize>) {
+        let socket = dir.join("cri.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let status_calls = Arc::new(AtomicUsize::new(0));
+        let runtime = MockRuntime {
+            status_calls: status_calls.clone(),
+        };
+        tokio::spawn(
+            Server::builder().serve_with_incoming(runtime, UnixListenerStream::new(listener)),
+        );
+        (socket, status_calls)
+    }
+
+    #[tokio::test]
+    async fn client() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, status_calls) = serve(dir.path());
+        let mut client = CriClient::connect(&socket).await.unwrap();
+
+        let expected = [CriContainer {
+            id: "web-id".to_owned(),
+            name: "web".to_owned(),
+            image: "nginx:1.27".to_owned(),
+            pid: 4242,
+            pod_name: Some("web-0".to_owned()),
+            pod_namespace: Some("default".to_owned()),
+            pod_uid: Some(POD_UID.to_owned()),
+            pod_labels: BTreeMap::from([("app.kubernetes.io/name".to_owned(), "web".to_owned())]),
+        }];
+        assert_eq!(client.containers().await.unwrap(), expected);
+        assert_eq!(status_calls.load(Ordering::Relaxed), 2);
+
+        // The pid of a running container is only asked once.
+        assert_eq!(client.containers().await.unwrap(), expected);
+        assert_eq!(status_calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, _) = serve(dir.path());
+        let labels = Labels::from([
+            ("container".to_owned(), "web".to_owned()),
+            ("container_id".to_owned(), "web-id".to_owned()),
+            ("image".to_owned(), "nginx:1.27".to_owned()),
+            ("pod".to_owned(), "web-0".to_owned()),
+            ("namespace".to_owned(), "default".to_owned()),
+            ("pod_uid".to_owned(), POD_UID.to_owned()),
+        ]);
+
+        let mut source = CriSource::new(&socket).init_only();
+        assert_eq!(
+            source.targets().await.unwrap(),
+            [Target {
+                pid: 4242,
+                labels: labels.clone(),
+            }]
+        );
+
+        let mut with_pod_labels = labels.clone();
+        with_pod_labels.insert(
+            "pod_label_app_kubernetes_io_name".to_owned(),
+            "web".to_owned(),
+        );
+        let mut source = CriSource::new(&socket).init_only().with_pod_labels();
+        assert_eq!(
+            source.targets().await.unwrap(),
+            [Target {
+                pid: 4242,
+                labels: with_pod_labels,
+            }]
+        );
+
+        // Every process in the container's cgroup.
+        let proc_root = dir.path().join("proc");
+        let cgroup_root = dir.path().join("cgroup");
+        let cgroup = cgroup_root.join("kubepods/web");
+        fs::create_dir_all(proc_root.join("4242")).unwrap();
+        fs::write(proc_root.join("4242/cgroup"), "0::/kubepods/web\n").unwrap();
+        fs::create_dir_all(&cgroup).unwrap();
+        fs::write(cgroup.join("cgroup.procs"), "4242\n4250\n").unwrap();
+        let mut source = CriSource::new(&socket).with_roots(proc_root, cgroup_root);
+        assert_eq!(
+            source.targets().await.unwrap(),
+            [
+                Target {
+                    pid: 4242,
+                    labels: labels.clone(),
+                },
+                Target { pid: 4250, labels },
+            ]
+        );
+    }
+}
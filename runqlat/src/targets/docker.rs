@@ -0,0 +1,560 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use http_body_util::{BodyExt as _, Empty};
+use hyper::{
+    Request, Response,
+    body::{Bytes, Incoming},
+    client::conn::http1,
+    header::HOST,
+};
+use hyper_util::rt::TokioIo;
+use log::{debug, warn};
+use serde_json::Value;
+use tokio::{net::UnixStream, sync::Notify, task::JoinHandle};
+
+use crate::targets::{
+    Labels, Target, TargetSource, blocking,
+    cgroup::{self, CGROUP_ROOT},
+};
+
+/// Default socket of the Docker Engine API.
+pub const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// `/events` filtered to container starts and deaths:
+/// `filters={"type":["container"],"event":["start","die"]}`.
+const EVENTS_PATH: &str = concat!(
+    "/events?filters=",
+    "%7B%22type%22%3A%5B%22container%22%5D%2C",
+    "%22event%22%3A%5B%22start%22%2C%22die%22%5D%7D",
+);
+
+/// Running Docker container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DockerContainer {
+    pub id: String,
+    /// Name without the leading `/`.
+    pub name: String,
+    pub image: String,
+    /// PID of the container's init process.
+    pub pid: u32,
+}
+
+/// Docker Engine API client over a unix socket.
+#[derive(Debug, Clone)]
+pub struct DockerClient {
+    socket: PathBuf,
+}
+
+impl DockerClient {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    async fn get(&self, path: &str) -> anyhow::Result<Response<Incoming>> {
+        let stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("failed to connect to {}", self.socket.display()))?;
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("docker connection closed: {e}");
+            }
+        });
+
+        let request = Request::get(path)
+            .header(HOST, "docker")
+            .body(Empty::<Bytes>::new())?;
+        let response = sender
+            .send_request(request)
+            .await
+            .with_context(|| format!("GET {path} failed"))?;
+        if !response.status().is_success() {
+            bail!("GET {path} failed: {}", response.status());
+        }
+        Ok(response)
+    }
+
+    async fn get_json(&self, path: &str) -> anyhow::Result<Value> {
+        let body = self
+            .get(path)
+            .await?
+            .into_body()
+            .collect()
+            .await?
+            .to_bytes();
+        serde_json::from_slice(&body).with_context(|| format!("invalid response to GET {path}"))
+    }
+
+    /// Running containers.
+    pub async fn containers(&self) -> anyhow::Result<Vec<DockerContainer>> {
+        let list = self.get_json("/containers/json").await?;
+        let ids = list
+            .as_array()
+            .ok_or_else(|| anyhow!("invalid container list"))?
+            .iter()
+            .filter_map(|container| container["Id"].as_str());
+
+        let mut containers = Vec::new();
+        for id in ids {
+            match self.inspect(id).await {
+                Ok(Some(container)) => containers.push(container),
+                Ok(None) => {}
+                // The container may have been removed since it was listed.
+                Err(e) => warn!("container {id}: {e:#}"),
+            }
+        }
+        Ok(containers)
+    }
+
+    /// Details of container `id`, or `None` if it is not running.
+    pub async fn inspect(&self, id: &str) -> anyhow::Result<Option<DockerContainer>> {
+        let container = self.get_json(&format!("/containers/{id}/json")).await?;
+        let pid = container["State"]["Pid"]
+            .as_u64()
+            .and_then(|pid| u32::try_from(pid).ok())
+            .unwrap_or(0);
+        if pid == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(DockerContainer {
+            id: container["Id"].as_str().unwrap_or(id).to_owned(),
+            name: container["Name"]
+                .as_str()
+                .unwrap_or_default()
+                .trim_start_matches('/')
+                .to_owned(),
+            image: container["Config"]["Image"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            pid,
+        }))
+    }
+
+    /// PIDs of all processes of container `id`, as reported by `docker top`.
+    pub async fn top(&self, id: &str) -> anyhow::Result<Vec<u32>> {
+        let top = self
+            .get_json(&format!("/containers/{id}/top?ps_args=-o%20pid"))
+            .await?;
+        let column = top["Titles"]
+            .as_array()
+            .and_then(|titles| titles.iter().position(|title| title == "PID"))
+            .ok_or_else(|| anyhow!("no PID column in top output"))?;
+
+        Ok(top["Processes"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|process| process[column].as_str()?.parse().ok())
+            .collect())
+    }
+
+    /// Subscribes to container `start` and `die` events.
+    pub async fn events(&self) -> anyhow::Result<DockerEvents> {
+        Ok(DockerEvents {
+            body: self.get(EVENTS_PATH).await?.into_body(),
+            buf: Vec::new(),
+        })
+    }
+}
+
+/// Stream of container events returned by [`DockerClient::events`].
+pub struct DockerEvents {
+    body: Incoming,
+    buf: Vec<u8>,
+}
+
+impl DockerEvents {
+    /// Next event as its action and container id, or `None` once the stream ended.
+    pub async fn next(&mut self) -> anyhow::Result<Option<(String, String)>> {
+        loop {
+            // Events are newline delimited JSON objects, which may span several frames.
+            while let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                let event: Value = match serde_json::from_slice(&line) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("invalid docker event: {e}");
+                        continue;
+                    }
+                };
+                let action = event["Action"].as_str();
+                let id = event["Actor"]["ID"].as_str();
+                if let (Some(action), Some(id)) = (action, id) {
+                    return Ok(Some((action.to_owned(), id.to_owned())));
+                }
+            }
+
+            match self.body.frame().await {
+                None => return Ok(None),
+                Some(frame) => {
+                    if let Ok(data) = frame?.into_data() {
+                        self.buf.extend_from_slice(&data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+type Containers = Arc<Mutex<HashMap<String, DockerContainer>>>;
+
+/// Tracks every running Docker container.
+///
+/// The container list is loaded once and then kept up to date from the `/events` stream, which
+/// is resubscribed (and the list reloaded) if the stream ends. Targets are labeled with
+/// `container`, `container_id` and `image`. By default every process in the container's cgroup
+/// is tracked, falling back to `docker top` when the cgroup cannot be read.
+pub struct DockerSource {
+    client: DockerClient,
+    containers: Containers,
+    events: Option<JoinHandle<()>>,
+    changed: Option<Arc<Notify>>,
+    all_processes: bool,
+    proc_root: PathBuf,
+    cgroup_root: PathBuf,
+}
+
+impl DockerSource {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            client: DockerClient::new(socket),
+            containers: Containers::default(),
+            events: None,
+            changed: None,
+            all_processes: true,
+            proc_root: PathBuf::from("/proc"),
+            cgroup_root: PathBuf::from(CGROUP_ROOT),
+        }
+    }
+
+    /// Tracks only the init process of each container.
+    pub fn init_only(mut self) -> Self {
+        self.all_processes = false;
+        self
+    }
+
+    /// Procfs and cgroup v2 mounts used to find the processes of a container.
+    pub fn with_roots(
+        mut self,
+        proc_root: impl Into<PathBuf>,
+        cgroup_root: impl Into<PathBuf>,
+    ) -> Self {
+        self.proc_root = proc_root.into();
+        self.cgroup_root = cgroup_root.into();
+        self
+    }
+
+    /// Subscribes to events, then loads the container list, so no container started or stopped
+    /// in between is missed: events are only applied once the list is, and replaying those that
+    /// happened before it was loaded is harmless.
+    async fn resync(&mut self) -> anyhow::Result<()> {
+        if let Some(events) = self.events.take() {
+            events.abort();
+        }
+        // Returns once the engine answered, i.e. the subscription is active.
+        let events = self.client.events().await?;
+        let containers = self.client.containers().await?;
+        *self
+            .containers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = containers
+            .into_iter()
+            .map(|container| (container.id.clone(), container))
+            .collect();
+
+        self.events = Some(tokio::spawn(watch_events(
+            self.client.clone(),
+            events,
+            self.containers.clone(),
+            self.changed.clone(),
+        )));
+        Ok(())
+    }
+
+    async fn pids(&self, container: &DockerContainer) -> Vec<u32> {
+        if !self.all_processes {
+            return vec![container.pid];
+        }
+        let (proc_root, cgroup_root) = (self.proc_root.clone(), self.cgroup_root.clone());
+        let pid = container.pid;
+        let procs = blocking(move || Ok(cgroup::cgroup_procs(&proc_root, &cgroup_root, pid)?));
+        match procs.await {
+            Ok(pids) if !pids.is_empty() => return pids,
+            Ok(_) => {}
+            Err(e) => debug!("container {}: failed to read cgroup: {e:#}", container.id),
+        }
+        match self.client.top(&container.id).await {
+            Ok(pids) if !pids.is_empty() => pids,
+            Ok(_) => vec![container.pid],
+            Err(e) => {
+                warn!("container {}: {e:#}", container.id);
+                vec![container.pid]
+            }
+        }
+    }
+}
+
+impl Drop for DockerSource {
+    fn drop(&mut self) {
+        if let Some(events) = &self.events {
+            events.abort();
+        }
+    }
+}
+
+async fn watch_events(
+    client: DockerClient,
+    events: DockerEvents,
+    containers: Containers,
+    changed: Option<Arc<Notify>>,
+) {
+    if let Err(e) = try_watch_events(&client, events, &containers, changed.as_deref()).await {
+        warn!("docker events: {e:#}");
+    }
+}
+
+async fn try_watch_events(
+    client: &DockerClient,
+    mut events: DockerEvents,
+    containers: &Containers,
+    changed: Option<&Notify>,
+) -> anyhow::Result<()> {
+    while let Some((action, id)) = events.next().await? {
+        let container = match action.as_str() {
+            "start" => match client.inspect(&id).await {
+                Ok(container) => container,
+                Err(e) => {
+                    warn!("container {id}: {e:#}");
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        {
+            let mut containers = containers.lock().unwrap_or_else(PoisonError::into_inner);
+            match container {
+                Some(container) => containers.insert(id, container),
+                None => containers.remove(&id),
+            };
+        }
+        if let Some(changed) = changed {
+            changed.notify_one();
+        }
+    }
+    Ok(())
+}
+
+impl TargetSource for DockerSource {
+    fn name(&self) -> &str {
+        "docker"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        if self.events.as_ref().is_none_or(JoinHandle::is_finished) {
+            self.resync().await?;
+        }
+
+        let containers: Vec<DockerContainer> = self
+            .containers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+
+        let mut targets = Vec::new();
+        for container in containers {
+            let pids = self.pids(&container).await;
+            let mut labels = Labels::new();
+            labels.insert("container".to_owned(), container.name);
+            labels.insert("container_id".to_owned(), container.id);
+            labels.insert("image".to_owned(), container.image);
+            targets.extend(pids.into_iter().map(|pid| Target {
+                pid,
+                labels: labels.clone(),
+            }));
+        }
+        Ok(targets)
+    }
+
+    fn set_change_notifier(&mut self, changed: Arc<Notify>) {
+        self.changed = Some(changed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::UnixListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Docker Engine API serving containers `a`, `b` and `c`, listing `a` and `b` as running,
+    /// and streaming the events sent to the returned channel on `/events`.
+    fn serve(dir: &Path) -> (PathBuf, mpsc::UnboundedSender<Value>) {
+        let socket = dir.join("docker.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let events = Arc::new(tokio::sync::Mutex::new(rx));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(respond(stream, events.clone()));
+            }
+        });
+        (socket, tx)
+    }
+
+    fn inspect(id: &str) -> Option<Value> {
+        let pid = match id {
+            "a" => 10,
+            "b" => 20,
+            "c" => 30,
+            _ => return None,
+        };
+        Some(json!({
+            "Id": id,
+            "Name": format!("/{id}-name"),
+            "Config": {"Image": "nginx:1.27"},
+            "State": {"Pid": pid},
+        }))
+    }
+
+    async fn respond(
+        mut stream: UnixStream,
+        events: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Value>>>,
+    ) {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let Ok(byte) = stream.read_u8().await else {
+                return;
+            };
+            head.push(byte);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let path = head.split_whitespace().nth(1).unwrap();
+
+        if path == EVENTS_PATH {
+            let header = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+            stream.write_all(header.as_bytes()).await.unwrap();
+            let mut events = events.lock().await;
+            while let Some(event) = events.recv().await {
+                let line = format!("{event}\n");
+                let chunk = format!("{:x}\r\n{line}\r\n", line.len());
+                stream.write_all(chunk.as_bytes()).await.unwrap();
+            }
+            return;
+        }
+
+        let id = path
+            .strip_prefix("/containers/")
+            .and_then(|path| path.split_once('/'))
+            .map(|(id, _)| id);
+        let body = match (path, id) {
+            ("/containers/json", _) => Some(json!([{"Id": "a"}, {"Id": "b"}])),
+            (path, Some(id)) if path.ends_with("/json") => inspect(id),
+            (path, Some("a")) if path.ends_with("/top?ps_args=-o%20pid") => Some(json!({
+                "Titles": ["PID"],
+                "Processes": [["10"], ["11"]],
+            })),
+            _ => None,
+        };
+        let response = match body {
+            Some(body) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                body.to_string().len()
+            ),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned(),
+        };
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    fn event(action: &str, id: &str) -> Value {
+        json!({"Type": "container", "Action": action, "Actor": {"ID": id, "Attributes": {}}})
+    }
+
+    fn pids(targets: Vec<Target>) -> Vec<u32> {
+        let mut pids: Vec<_> = targets.into_iter().map(|target| target.pid).collect();
+        pids.sort_unstable();
+        pids
+    }
+
+    #[tokio::test]
+    async fn client() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, _events) = serve(dir.path());
+        let client = DockerClient::new(socket);
+
+        let container = |id: &str, pid| DockerContainer {
+            id: id.to_owned(),
+            name: format!("{id}-name"),
+            image: "nginx:1.27".to_owned(),
+            pid,
+        };
+        assert_eq!(
+            client.containers().await.unwrap(),
+            [container("a", 10), container("b", 20)]
+        );
+        assert_eq!(client.top("a").await.unwrap(), [10, 11]);
+        assert!(client.inspect("missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, events) = serve(dir.path());
+        let changed = Arc::new(Notify::new());
+        let mut source = DockerSource::new(socket).init_only();
+        source.set_change_notifier(changed.clone());
+
+        // `b` died after the subscription but before the list was loaded.
+        events.send(event("die", "b")).unwrap();
+        let targets = source.targets().await.unwrap();
+        assert_eq!(
+            targets
+                .iter()
+                .find(|target| target.pid == 10)
+                .unwrap()
+                .labels,
+            Labels::from([
+                ("container".to_owned(), "a-name".to_owned()),
+                ("container_id".to_owned(), "a".to_owned()),
+                ("image".to_owned(), "nginx:1.27".to_owned()),
+            ])
+        );
+        tokio::time::timeout(Duration::from_secs(5), changed.notified())
+            .await
+            .unwrap();
+        assert_eq!(pids(source.targets().await.unwrap()), [10]);
+
+        events.send(event("start", "c")).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), changed.notified())
+            .await
+            .unwrap();
+        assert_eq!(pids(source.targets().await.unwrap()), [10, 30]);
+    }
+
+    #[tokio::test]
+    async fn top_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, _events) = serve(dir.path());
+        // Without cgroups to read, processes are listed with `docker top`.
+        let mut source = DockerSource::new(socket).with_roots(dir.path(), dir.path());
+        assert_eq!(pids(source.targets().await.unwrap()), [10, 11, 20]);
+    }
+}
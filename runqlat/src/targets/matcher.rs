@@ -0,0 +1,271 @@
+use std::path::PathBuf;
+
+use regex::Regex;
+
+use crate::targets::{Labels, ProcEventSource, Process, TargetSource};
+
+/// Selects processes by name, command line, executable or user.
+///
+/// A process matches if, for every kind of criterion that was given, it matches at least one of
+/// its values; e.g. `comm("nginx").comm("php-fpm").uid(33)` matches nginx and php-fpm processes
+/// of uid 33. An empty matcher matches nothing.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessMatcher {
+    comm: Vec<String>,
+    comm_regex: Vec<Regex>,
+    cmdline: Vec<Regex>,
+    exe: Vec<PathBuf>,
+    uid: Vec<u32>,
+}
+
+impl ProcessMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Task name equal to `comm`. Names longer than 15 bytes are truncated by the kernel.
+    pub fn comm(mut self, comm: impl Into<String>) -> Self {
+        self.comm.push(comm.into());
+        self
+    }
+
+    /// Task name matching `regex`.
+    pub fn comm_regex(mut self, regex: Regex) -> Self {
+        self.comm_regex.push(regex);
+        self
+    }
+
+    /// Command line, arguments joined by spaces, matching `regex`.
+    pub fn cmdline(mut self, regex: Regex) -> Self {
+        self.cmdline.push(regex);
+        self
+    }
+
+    /// Executable at `path`.
+    pub fn exe(mut self, path: impl Into<PathBuf>) -> Self {
+        self.exe.push(path.into());
+        self
+    }
+
+    /// Real user id `uid`.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid.push(uid);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.comm.is_empty()
+            && self.comm_regex.is_empty()
+            && self.cmdline.is_empty()
+            && self.exe.is_empty()
+            && self.uid.is_empty()
+    }
+
+    /// Whether `process` matches. Fields that cannot be read, e.g. because the process exited,
+    /// do not match.
+    pub fn matches(&self, process: &Process) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        if !self.comm.is_empty() || !self.comm_regex.is_empty() {
+            let Ok(comm) = process.comm() else {
+                return false;
+            };
+            if !self.comm.contains(&comm) && !self.comm_regex.iter().any(|re| re.is_match(&comm)) {
+                return false;
+            }
+        }
+        if !self.cmdline.is_empty() {
+            let Ok(cmdline) = process.cmdline() else {
+                return false;
+            };
+            let cmdline = cmdline.join(" ");
+            if !self.cmdline.iter().any(|re| re.is_match(&cmdline)) {
+                return false;
+            }
+        }
+        if !self.exe.is_empty() {
+            let Ok(exe) = process.exe() else {
+                return false;
+            };
+            if !self.exe.contains(&exe) {
+                return false;
+            }
+        }
+        if !self.uid.is_empty() {
+            let Ok(uid) = process.uid() else {
+                return false;
+            };
+            if !self.uid.contains(&uid) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Source tracking matching processes, labeled with their `comm` and `uid`. Processes are
+    /// matched as they fork and exec, see [`ProcEventSource`].
+    pub fn into_source(self) -> impl TargetSource {
+        ProcEventSource::new(move |process: &Process| {
+            if !self.matches(process) {
+                return None;
+            }
+            let mut labels = Labels::new();
+            labels.insert("comm".to_owned(), process.comm().ok()?);
+            if let Ok(uid) = process.uid() {
+                labels.insert("uid".to_owned(), uid.to_string());
+            }
+            Some(labels)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink, path::Path};
+
+    use super::*;
+
+    /// nginx worker 4170 of uid 33 in the procfs at `proc_root`.
+    fn nginx(proc_root: &Path) -> Process {
+        let dir = proc_root.join("4170");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("comm"), "nginx\n").unwrap();
+        fs::write(
+            dir.join("cmdline"),
+            "nginx: worker process\0-g\0daemon off;\0",
+        )
+        .unwrap();
+        symlink("/usr/sbin/nginx", dir.join("exe")).unwrap();
+        fs::write(
+            dir.join("status"),
+            "Name:\tnginx\nUmask:\t0022\nState:\tS (sleeping)\nTgid:\t4170\nPid:\t4170\n\
+             PPid:\t4169\nUid:\t33\t33\t33\t33\nGid:\t33\t33\t33\t33\n",
+        )
+        .unwrap();
+        Process::new(proc_root, 4170)
+    }
+
+    fn regex(regex: &str) -> Regex {
+        Regex::new(regex).unwrap()
+    }
+
+    #[test]
+    fn comm() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = nginx(proc_root.path());
+
+        assert!(ProcessMatcher::new().comm("nginx").matches(&process));
+        assert!(!ProcessMatcher::new().comm("ngin").matches(&process));
+        assert!(
+            ProcessMatcher::new()
+                .comm("php-fpm")
+                .comm("nginx")
+                .matches(&process)
+        );
+        assert!(
+            ProcessMatcher::new()
+                .comm_regex(regex("^ng"))
+                .matches(&process)
+        );
+        assert!(
+            !ProcessMatcher::new()
+                .comm_regex(regex("^php"))
+                .matches(&process)
+        );
+        // comm and comm_regex are the same kind of criterion
+        assert!(
+            ProcessMatcher::new()
+                .comm("php-fpm")
+                .comm_regex(regex("^ng"))
+                .matches(&process)
+        );
+    }
+
+    #[test]
+    fn cmdline() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = nginx(proc_root.path());
+
+        // arguments are joined by spaces
+        assert!(
+            ProcessMatcher::new()
+                .cmdline(regex("worker process -g daemon off;$"))
+                .matches(&process)
+        );
+        assert!(
+            !ProcessMatcher::new()
+                .cmdline(regex("master"))
+                .matches(&process)
+        );
+    }
+
+    #[test]
+    fn exe() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = nginx(proc_root.path());
+
+        assert!(
+            ProcessMatcher::new()
+                .exe("/usr/sbin/nginx")
+                .matches(&process)
+        );
+        assert!(
+            !ProcessMatcher::new()
+                .exe("/usr/bin/nginx")
+                .matches(&process)
+        );
+    }
+
+    #[test]
+    fn uid() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = nginx(proc_root.path());
+
+        assert!(ProcessMatcher::new().uid(33).matches(&process));
+        assert!(ProcessMatcher::new().uid(0).uid(33).matches(&process));
+        assert!(!ProcessMatcher::new().uid(0).matches(&process));
+    }
+
+    #[test]
+    fn every_kind_of_criterion() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = nginx(proc_root.path());
+
+        let matcher = ProcessMatcher::new()
+            .comm("nginx")
+            .comm("php-fpm")
+            .cmdline(regex("worker"))
+            .exe("/usr/sbin/nginx")
+            .uid(33);
+        assert!(matcher.matches(&process));
+        // every kind must match, any of its values
+        assert!(matcher.clone().uid(0).matches(&process));
+        assert!(!ProcessMatcher::new().comm("nginx").uid(0).matches(&process));
+        assert!(
+            !ProcessMatcher::new()
+                .comm("nginx")
+                .cmdline(regex("master"))
+                .matches(&process)
+        );
+        assert!(
+            !ProcessMatcher::new()
+                .comm("php-fpm")
+                .uid(33)
+                .matches(&process)
+        );
+    }
+
+    #[test]
+    fn empty_or_unreadable() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = nginx(proc_root.path());
+        assert!(!ProcessMatcher::new().matches(&process));
+
+        // the process exited
+        let process = Process::new(proc_root.path(), 4171);
+        assert!(!ProcessMatcher::new().comm("nginx").matches(&process));
+        assert!(!ProcessMatcher::new().uid(33).matches(&process));
+    }
+}
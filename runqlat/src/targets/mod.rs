@@ -0,0 +1,164 @@
+pub mod cgroup;
+#[cfg(feature = "cri")]
+pub mod cri;
+#[cfg(feature = "docker")]
+pub mod docker;
+mod matcher;
+mod pidfile;
+mod proc_events;
+mod procfs;
+mod reconciler;
+mod systemd;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use log::debug;
+use tokio::sync::Notify;
+
+pub use cgroup::CgroupSource;
+#[cfg(feature = "cri")]
+pub use cri::CriSource;
+#[cfg(feature = "docker")]
+pub use docker::DockerSource;
+pub use matcher::ProcessMatcher;
+pub use pidfile::PidFile;
+pub use proc_events::{ProcConnector, ProcEvent, ProcEventSource};
+pub use procfs::{ProcScanner, Process, processes};
+pub use reconciler::Reconciler;
+pub use systemd::{SystemdSource, unit_cgroup, unit_name};
+
+/// Labels describing a target, e.g. `container` -> `nginx`.
+pub type Labels = BTreeMap<String, String>;
+
+/// Process to track and the labels describing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// tgid (process id)
+    pub pid: u32,
+    pub labels: Labels,
+}
+
+impl Target {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            labels: Labels::new(),
+        }
+    }
+
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// Discovers the processes that should be tracked, polled by a [`Reconciler`].
+///
+/// Every call returns the complete set of targets the source currently wants tracked; the
+/// reconciler computes the difference with what is already tracked.
+pub trait TargetSource: Send + 'static {
+    /// Name used in logs.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    fn targets(&mut self) -> impl Future<Output = anyhow::Result<Vec<Target>>> + Send;
+
+    /// Called once when the source is added to a reconciler. Event driven sources keep `changed`
+    /// and notify it whenever their targets change, so the reconciler polls them right away
+    /// instead of waiting for its next interval.
+    fn set_change_notifier(&mut self, changed: Arc<Notify>) {
+        let _ = changed;
+    }
+}
+
+/// Fixed list of targets.
+///
+/// Each process is pinned by its start time when first seen, so a target whose pid is reused by
+/// another process after it exited is dropped rather than tracking the new process.
+#[derive(Debug, Clone, Default)]
+pub struct StaticTargets {
+    targets: Vec<Target>,
+    /// pid -> start time of the process first seen with it
+    start_times: HashMap<u32, u64>,
+}
+
+impl StaticTargets {
+    pub fn new(targets: Vec<Target>) -> Self {
+        Self {
+            targets,
+            start_times: HashMap::new(),
+        }
+    }
+
+    pub fn from_pids(pids: impl IntoIterator<Item = u32>) -> Self {
+        Self::new(pids.into_iter().map(Target::new).collect())
+    }
+}
+
+impl TargetSource for StaticTargets {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        let pids: Vec<_> = self.targets.iter().map(|target| target.pid).collect();
+        let current = blocking(move || {
+            Ok(pids
+                .into_iter()
+                .map(|pid| Process::new(Path::new("/proc"), pid).start_time().ok())
+                .collect::<Vec<_>>())
+        })
+        .await?;
+
+        let mut current = current.into_iter();
+        let start_times = &mut self.start_times;
+        self.targets.retain(|target| {
+            let Some(start_time) = current.next().flatten() else {
+                return true;
+            };
+            let pinned = *start_times.entry(target.pid).or_insert(start_time);
+            if pinned != start_time {
+                debug!("pid {} was reused, no longer tracking it", target.pid);
+            }
+            pinned == start_time
+        });
+        Ok(self.targets.clone())
+    }
+}
+
+/// Runs `f`, which reads procfs or another filesystem, on the blocking thread pool so that
+/// sources do not stall the runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("blocking task failed")?
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Object safe version of [`TargetSource`] so a reconciler can hold sources of different types.
+trait DynTargetSource: Send {
+    fn name(&self) -> &str;
+
+    fn targets(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<Target>>>;
+}
+
+impl<T: TargetSource> DynTargetSource for T {
+    fn name(&self) -> &str {
+        TargetSource::name(self)
+    }
+
+    fn targets(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<Target>>> {
+        Box::pin(TargetSource::targets(self))
+    }
+}
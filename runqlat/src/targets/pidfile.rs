@@ -0,0 +1,104 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+
+use crate::targets::{Labels, Target, TargetSource, blocking};
+
+/// Tracks the process whose PID is written in a pidfile, e.g. `/run/nginx.pid`.
+///
+/// A missing pidfile means the service is not running and yields no targets.
+#[derive(Debug, Clone)]
+pub struct PidFile {
+    path: PathBuf,
+    name: String,
+    labels: Labels,
+}
+
+impl PidFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            name: format!("pidfile {}", path.display()),
+            path,
+            labels: Labels::new(),
+        }
+    }
+
+    /// Labels attached to the target.
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+}
+
+impl TargetSource for PidFile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        let path = self.path.clone();
+        let Some(pid) = blocking(move || read_pid(&path)).await? else {
+            return Ok(Vec::new());
+        };
+
+        Ok(vec![Target {
+            pid,
+            labels: self.labels.clone(),
+        }])
+    }
+}
+
+/// Reads the pid in the pidfile at `path`, `None` if it does not exist.
+fn read_pid(path: &Path) -> anyhow::Result<Option<u32>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let pid = content
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} is empty", path.display()))?
+        .parse()
+        .with_context(|| format!("invalid pid in {}", path.display()))?;
+    Ok(Some(pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(content: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("service.pid");
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn read_pid_whitespace() {
+        let (_dir, path) = write("  1234\n");
+        assert_eq!(read_pid(&path).unwrap(), Some(1234));
+        // only the first word is the pid
+        let (_dir, path) = write("1234 extra\n");
+        assert_eq!(read_pid(&path).unwrap(), Some(1234));
+    }
+
+    #[test]
+    fn read_pid_garbage() {
+        for content in ["", " \n", "nginx\n", "-1\n", "12ab\n"] {
+            let (_dir, path) = write(content);
+            assert!(read_pid(&path).is_err(), "{content:?}");
+        }
+    }
+
+    #[test]
+    fn read_pid_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_pid(&dir.path().join("service.pid")).unwrap(), None);
+    }
+}
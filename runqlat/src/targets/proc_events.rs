@@ -0,0 +1,532 @@
+use std::{
+    collections::HashMap,
+    io, mem,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::Context as _;
+use log::{debug, warn};
+use tokio::{io::unix::AsyncFd, sync::Notify, task::JoinHandle};
+
+use crate::targets::{Labels, Process, Target, TargetSource, blocking, processes};
+
+// From linux/connector.h and linux/cn_proc.h.
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_CN_MCAST_IGNORE: u32 = 2;
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HDRLEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+/// Offset of `proc_event.event_data` from the start of the netlink message.
+const EVENT_DATA: usize = NLMSG_HDRLEN + CN_MSG_LEN + 16;
+
+/// Process lifecycle event reported by the proc connector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcEvent {
+    /// A task was created. `child_pid == child_tgid` for new processes, otherwise a thread.
+    Fork {
+        parent_tgid: u32,
+        child_pid: u32,
+        child_tgid: u32,
+    },
+    /// A process called `execve`.
+    Exec { tgid: u32 },
+    /// A task exited. `pid == tgid` when the thread group leader exited.
+    Exit { pid: u32, tgid: u32 },
+}
+
+/// Netlink socket subscribed to the kernel's proc connector.
+///
+/// Receiving events requires `CAP_NET_ADMIN` in the initial network namespace.
+pub struct ProcConnector {
+    fd: AsyncFd<OwnedFd>,
+    buf: Vec<u8>,
+}
+
+impl ProcConnector {
+    /// Opens a connector socket and subscribes to process events.
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = CN_IDX_PROC;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                (&raw const addr).cast(),
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        send_op(&fd, PROC_CN_MCAST_LISTEN)?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            buf: vec![0; 8192],
+        })
+    }
+
+    /// Waits for the next batch of events.
+    ///
+    /// Fails with `ENOBUFS` when the socket buffer overflowed and events were lost.
+    pub async fn recv(&mut self) -> io::Result<Vec<ProcEvent>> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let fd = guard.get_inner().as_raw_fd();
+            let buf = &mut self.buf;
+            let result = guard.try_io(|_| {
+                let n = unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), 0) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            if let Ok(n) = result {
+                return Ok(parse_events(&self.buf[..n?]));
+            }
+        }
+    }
+}
+
+impl Drop for ProcConnector {
+    fn drop(&mut self) {
+        // Older kernels keep generating events until every listener unsubscribed.
+        let _ = send_op(self.fd.get_ref(), PROC_CN_MCAST_IGNORE);
+    }
+}
+
+fn send_op(fd: &OwnedFd, op: u32) -> io::Result<()> {
+    let len = NLMSG_HDRLEN + CN_MSG_LEN + 4;
+    let mut msg = Vec::with_capacity(len);
+    // struct nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&std::process::id().to_ne_bytes());
+    // struct cn_msg
+    msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+    msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&4u16.to_ne_bytes());
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    // enum proc_cn_mcast_op
+    msg.extend_from_slice(&op.to_ne_bytes());
+
+    let n = unsafe { libc::send(fd.as_raw_fd(), msg.as_ptr().cast(), msg.len(), 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// Parses the fork, exec and exit events out of a datagram of netlink messages.
+fn parse_events(mut buf: &[u8]) -> Vec<ProcEvent> {
+    let mut events = Vec::new();
+    while let Some(len) = read_u32(buf, 0) {
+        let len = len as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        let msg = &buf[..len];
+        let event = read_u32(msg, NLMSG_HDRLEN + CN_MSG_LEN).and_then(|what| {
+            let data = |i: usize| read_u32(msg, EVENT_DATA + 4 * i);
+            match what {
+                PROC_EVENT_FORK => Some(ProcEvent::Fork {
+                    parent_tgid: data(1)?,
+                    child_pid: data(2)?,
+                    child_tgid: data(3)?,
+                }),
+                PROC_EVENT_EXEC => Some(ProcEvent::Exec { tgid: data(1)? }),
+                PROC_EVENT_EXIT => Some(ProcEvent::Exit {
+                    pid: data(0)?,
+                    tgid: data(1)?,
+                }),
+                _ => None,
+            }
+        });
+        events.extend(event);
+        // Messages are aligned to 4 bytes.
+        buf = buf.get(len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    events
+}
+
+#[derive(Default)]
+struct State {
+    targets: HashMap<u32, Labels>,
+    /// Set when events may have been lost, so the next poll rescans procfs.
+    resync: bool,
+    /// Events received while procfs is rescanned, applied once the scan is done.
+    pending: Option<Vec<ProcEvent>>,
+}
+
+/// Rescans the procfs at `root` into `state`. Reads procfs, so it runs on the blocking pool,
+/// without holding the lock of `state` meanwhile.
+fn rescan<F>(root: &Path, filter: &Mutex<F>, state: &Mutex<State>) -> anyhow::Result<()>
+where
+    F: FnMut(&Process) -> Option<Labels>,
+{
+    {
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.resync = false;
+        state.pending = Some(Vec::new());
+    }
+    let processes = match processes(root) {
+        Ok(processes) => processes,
+        Err(e) => {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.pending = None;
+            state.resync = true;
+            return Err(e).with_context(|| format!("failed to list {}", root.display()));
+        }
+    };
+    let mut targets: HashMap<u32, Labels> = {
+        let mut filter = filter.lock().unwrap_or_else(PoisonError::into_inner);
+        processes
+            .filter_map(|process| Some((process.pid(), (*filter)(&process)?)))
+            .collect()
+    };
+    loop {
+        let events = {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            match state.pending.take() {
+                Some(events) if !events.is_empty() => {
+                    state.pending = Some(Vec::new());
+                    events
+                }
+                _ => {
+                    state.targets = targets;
+                    return Ok(());
+                }
+            }
+        };
+        let mut filter = filter.lock().unwrap_or_else(PoisonError::into_inner);
+        for event in events {
+            apply(&mut targets, &mut *filter, root, event);
+        }
+    }
+}
+
+/// Applies `event` to `targets`, returning whether they changed.
+fn apply<F>(
+    targets: &mut HashMap<u32, Labels>,
+    filter: &mut F,
+    root: &Path,
+    event: ProcEvent,
+) -> bool
+where
+    F: FnMut(&Process) -> Option<Labels>,
+{
+    let (tgid, exited) = match event {
+        // Threads share their process's target.
+        ProcEvent::Fork {
+            child_pid,
+            child_tgid,
+            ..
+        } if child_pid == child_tgid => (child_tgid, false),
+        ProcEvent::Fork { .. } => return false,
+        // The new program may (no longer) match.
+        ProcEvent::Exec { tgid } => (tgid, false),
+        ProcEvent::Exit { pid, tgid } if pid == tgid => (tgid, true),
+        ProcEvent::Exit { .. } => return false,
+    };
+    let labels = if exited {
+        None
+    } else {
+        filter(&Process::new(root, tgid))
+    };
+    match labels {
+        Some(labels) => targets.insert(tgid, labels.clone()) != Some(labels),
+        None => targets.remove(&tgid).is_some(),
+    }
+}
+
+/// Tracks every process accepted by a filter, like [`ProcScanner`](super::ProcScanner), but
+/// follows forks, execs and exits through the netlink proc connector.
+///
+/// The filter is evaluated when a process is forked or calls `execve`, and the reconciler is
+/// notified right away, so even short-lived processes get tracked. Procfs is scanned once at
+/// startup and again whenever events were lost. If the connector is unavailable, e.g. without
+/// `CAP_NET_ADMIN` or in a network namespace, the source falls back to scanning procfs on every
+/// poll.
+pub struct ProcEventSource<F> {
+    root: PathBuf,
+    /// Shared with the listener and the blocking task rescanning procfs.
+    filter: Arc<Mutex<F>>,
+    state: Arc<Mutex<State>>,
+    listener: Option<JoinHandle<()>>,
+    polling: bool,
+    changed: Option<Arc<Notify>>,
+}
+
+impl<F> ProcEventSource<F>
+where
+    F: FnMut(&Process) -> Option<Labels> + Send + 'static,
+{
+    pub fn new(filter: F) -> Self {
+        Self::with_root("/proc", filter)
+    }
+
+    /// Reads processes from the procfs mounted at `root`. Events are still received for the
+    /// PID namespace of the initial network namespace, so `root` should be the host's procfs.
+    pub fn with_root(root: impl Into<PathBuf>, filter: F) -> Self {
+        Self {
+            root: root.into(),
+            filter: Arc::new(Mutex::new(filter)),
+            state: Arc::new(Mutex::new(State {
+                resync: true,
+                ..State::default()
+            })),
+            listener: None,
+            polling: false,
+            changed: None,
+        }
+    }
+
+    fn start_listener(&mut self) {
+        let connector = match ProcConnector::open() {
+            Ok(connector) => connector,
+            Err(e) => {
+                warn!(
+                    "proc connector unavailable, polling {}: {e}",
+                    self.root.display()
+                );
+                self.polling = true;
+                return;
+            }
+        };
+        // Events received before the next scan are covered by it.
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .resync = true;
+        self.listener = Some(tokio::spawn(listen(
+            connector,
+            self.root.clone(),
+            self.filter.clone(),
+            self.state.clone(),
+            self.changed.clone(),
+        )));
+    }
+}
+
+impl<F> Drop for ProcEventSource<F> {
+    fn drop(&mut self) {
+        if let Some(listener) = &self.listener {
+            listener.abort();
+        }
+    }
+}
+
+async fn listen<F>(
+    mut connector: ProcConnector,
+    root: PathBuf,
+    filter: Arc<Mutex<F>>,
+    state: Arc<Mutex<State>>,
+    changed: Option<Arc<Notify>>,
+) where
+    F: FnMut(&Process) -> Option<Labels>,
+{
+    loop {
+        let changes = match connector.recv().await {
+            Ok(events) => {
+                let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Some(pending) = &mut state.pending {
+                    // applied by the rescan, which then reports its targets
+                    pending.extend(events);
+                    continue;
+                }
+                let mut filter = filter.lock().unwrap_or_else(PoisonError::into_inner);
+                events.into_iter().fold(false, |changed, event| {
+                    apply(&mut state.targets, &mut *filter, &root, event) | changed
+                })
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                debug!("proc connector overrun, rescanning {}", root.display());
+                state.lock().unwrap_or_else(PoisonError::into_inner).resync = true;
+                true
+            }
+            Err(e) => {
+                warn!("proc connector: {e}");
+                return;
+            }
+        };
+        if changes && let Some(changed) = &changed {
+            changed.notify_one();
+        }
+    }
+}
+
+impl<F> TargetSource for ProcEventSource<F>
+where
+    F: FnMut(&Process) -> Option<Labels> + Send + 'static,
+{
+    fn name(&self) -> &str {
+        "proc-events"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        if !self.polling {
+            if self.listener.as_ref().is_some_and(JoinHandle::is_finished) {
+                self.listener = None;
+            }
+            if self.listener.is_none() {
+                self.start_listener();
+            }
+        }
+
+        let resync = self
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .resync;
+        if self.polling || self.listener.is_none() || resync {
+            let (root, filter, state) =
+                (self.root.clone(), self.filter.clone(), self.state.clone());
+            blocking(move || rescan(&root, &filter, &state)).await?;
+        }
+
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(state
+            .targets
+            .iter()
+            .map(|(&pid, labels)| Target {
+                pid,
+                labels: labels.clone(),
+            })
+            .collect())
+    }
+
+    fn set_change_notifier(&mut self, changed: Arc<Notify>) {
+        self.changed = Some(changed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Netlink message of a `proc_event` of type `what` with `data` as its `event_data`.
+    fn message(what: u32, data: &[u32]) -> Vec<u8> {
+        let event_len = 16 + 4 * data.len();
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + event_len;
+        let mut msg = Vec::with_capacity(len);
+        // struct nlmsghdr
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        // struct cn_msg
+        msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&(event_len as u16).to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        // struct proc_event: what, cpu, timestamp_ns, event_data
+        msg.extend_from_slice(&what.to_ne_bytes());
+        msg.extend_from_slice(&3u32.to_ne_bytes());
+        msg.extend_from_slice(&1_234_567_890u64.to_ne_bytes());
+        for value in data {
+            msg.extend_from_slice(&value.to_ne_bytes());
+        }
+        msg
+    }
+
+    #[test]
+    fn fork() {
+        // parent_pid, parent_tgid, child_pid, child_tgid
+        let msg = message(PROC_EVENT_FORK, &[100, 100, 200, 200]);
+        assert_eq!(
+            parse_events(&msg),
+            [ProcEvent::Fork {
+                parent_tgid: 100,
+                child_pid: 200,
+                child_tgid: 200,
+            }]
+        );
+    }
+
+    #[test]
+    fn exec() {
+        // process_pid, process_tgid
+        let msg = message(PROC_EVENT_EXEC, &[201, 200]);
+        assert_eq!(parse_events(&msg), [ProcEvent::Exec { tgid: 200 }]);
+    }
+
+    #[test]
+    fn exit() {
+        // process_pid, process_tgid, exit_code, exit_signal, parent_pid, parent_tgid
+        let msg = message(PROC_EVENT_EXIT, &[201, 200, 0, 17, 100, 100]);
+        assert_eq!(
+            parse_events(&msg),
+            [ProcEvent::Exit {
+                pid: 201,
+                tgid: 200
+            }]
+        );
+    }
+
+    #[test]
+    fn several_messages() {
+        let mut buf = message(PROC_EVENT_FORK, &[100, 100, 200, 200]);
+        // e.g. the acknowledgment of PROC_CN_MCAST_LISTEN
+        buf.extend(message(0, &[0]));
+        buf.extend(message(PROC_EVENT_EXIT, &[200, 200, 0, 17]));
+        assert_eq!(
+            parse_events(&buf),
+            [
+                ProcEvent::Fork {
+                    parent_tgid: 100,
+                    child_pid: 200,
+                    child_tgid: 200,
+                },
+                ProcEvent::Exit {
+                    pid: 200,
+                    tgid: 200
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = message(PROC_EVENT_EXEC, &[201, 200]);
+        let fork = message(PROC_EVENT_FORK, &[100, 100, 200, 200]);
+        buf.extend_from_slice(&fork[..fork.len() - 4]);
+        assert_eq!(parse_events(&buf), [ProcEvent::Exec { tgid: 200 }]);
+
+        // a message too short for its event
+        let mut exec = message(PROC_EVENT_EXEC, &[201]);
+        assert_eq!(parse_events(&exec), []);
+        exec.truncate(NLMSG_HDRLEN - 1);
+        assert_eq!(parse_events(&exec), []);
+    }
+}
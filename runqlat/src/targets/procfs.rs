@@ -0,0 +1,187 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::Context as _;
+
+use crate::targets::{Labels, Target, TargetSource, blocking};
+
+/// Process listed in a procfs mount. Fields are read on demand, so a process that exits between
+/// listing and reading yields `io::ErrorKind::NotFound`.
+#[derive(Debug, Clone)]
+pub struct Process {
+    pid: u32,
+    dir: PathBuf,
+}
+
+impl Process {
+    pub fn new(proc_root: &Path, pid: u32) -> Self {
+        Self {
+            pid,
+            dir: proc_root.join(pid.to_string()),
+        }
+    }
+
+    /// tgid (process id)
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Task name, at most 15 bytes long.
+    pub fn comm(&self) -> io::Result<String> {
+        let comm = fs::read_to_string(self.dir.join("comm"))?;
+        Ok(comm.trim_end_matches('\n').to_owned())
+    }
+
+    /// Command line arguments. Empty for kernel threads and zombies.
+    pub fn cmdline(&self) -> io::Result<Vec<String>> {
+        let cmdline = fs::read(self.dir.join("cmdline"))?;
+        Ok(cmdline
+            .split(|&b| b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect())
+    }
+
+    /// Path of the executable. Reading it requires the same privileges as ptrace.
+    pub fn exe(&self) -> io::Result<PathBuf> {
+        fs::read_link(self.dir.join("exe"))
+    }
+
+    /// Time the process started after system boot, in clock ticks. Together with the pid it
+    /// identifies a process, since pids are reused.
+    pub fn start_time(&self) -> io::Result<u64> {
+        let stat = fs::read_to_string(self.dir.join("stat"))?;
+        // comm, the second field, may contain spaces and parentheses.
+        stat.rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(19))
+            .and_then(|start_time| start_time.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid stat"))
+    }
+
+    /// Real user id.
+    pub fn uid(&self) -> io::Result<u32> {
+        let status = fs::read_to_string(self.dir.join("status"))?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|uids| uids.split_whitespace().next())
+            .and_then(|uid| uid.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Uid in status"))
+    }
+}
+
+/// Processes (not threads) listed in the procfs mounted at `proc_root`.
+pub fn processes(proc_root: &Path) -> io::Result<impl Iterator<Item = Process> + '_> {
+    Ok(fs::read_dir(proc_root)?.filter_map(move |entry| {
+        let pid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+        Some(Process::new(proc_root, pid))
+    }))
+}
+
+/// Scans procfs and tracks every process accepted by a filter.
+///
+/// The filter returns the labels of the target, or `None` to skip the process.
+pub struct ProcScanner<F> {
+    root: PathBuf,
+    /// Shared with the blocking task scanning procfs.
+    filter: Arc<Mutex<F>>,
+}
+
+impl<F> ProcScanner<F>
+where
+    F: FnMut(&Process) -> Option<Labels> + Send + 'static,
+{
+    pub fn new(filter: F) -> Self {
+        Self::with_root("/proc", filter)
+    }
+
+    /// Scans the procfs mounted at `root`, e.g. the host's `/proc` mounted in a container.
+    pub fn with_root(root: impl Into<PathBuf>, filter: F) -> Self {
+        Self {
+            root: root.into(),
+            filter: Arc::new(Mutex::new(filter)),
+        }
+    }
+}
+
+impl<F> TargetSource for ProcScanner<F>
+where
+    F: FnMut(&Process) -> Option<Labels> + Send + 'static,
+{
+    fn name(&self) -> &str {
+        "proc"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        let root = self.root.clone();
+        let filter = self.filter.clone();
+        blocking(move || {
+            let processes =
+                processes(&root).with_context(|| format!("failed to list {}", root.display()))?;
+
+            let mut filter = filter.lock().unwrap_or_else(PoisonError::into_inner);
+            Ok(processes
+                .filter_map(|process| {
+                    let labels = (*filter)(&process)?;
+                    Some(Target {
+                        pid: process.pid(),
+                        labels,
+                    })
+                })
+                .collect())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(stat: &str) -> (tempfile::TempDir, Process) {
+        let proc_root = tempfile::tempdir().unwrap();
+        fs::create_dir(proc_root.path().join("4170")).unwrap();
+        fs::write(proc_root.path().join("4170/stat"), stat).unwrap();
+        let process = Process::new(proc_root.path(), 4170);
+        (proc_root, process)
+    }
+
+    #[test]
+    fn start_time() {
+        let (_proc_root, process) = process(
+            "4170 (cat) R 4166 4170 4166 0 -1 4194304 81 0 0 0 0 0 0 0 20 0 1 0 488187 2703360 \
+             287 18446744073709551615 94497390067712 94497390087593 140731987617104 0 0 0 0 0 0 \
+             0 0 0 17 0 0 0 0 0 0\n",
+        );
+        assert_eq!(process.start_time().unwrap(), 488187);
+    }
+
+    #[test]
+    fn start_time_of_odd_comm() {
+        let (_proc_root, process) =
+            process("4170 (a) b (c) S 1 4170 4170 0 -1 4194560 0 0 0 0 0 0 0 0 20 0 1 0 42 0 0\n");
+        assert_eq!(process.start_time().unwrap(), 42);
+    }
+
+    #[test]
+    fn start_time_of_truncated_stat() {
+        let (_proc_root, process) = process("4170 (cat) R 4166 4170\n");
+        assert_eq!(
+            process.start_time().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn start_time_of_exited_process() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = Process::new(proc_root.path(), 4170);
+        assert_eq!(
+            process.start_time().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::sync::Notify;
+
+use crate::{
+    ProcInfo, Profiler, TrackedChanges,
+    snapshot::{MIN_PERIOD, Ticker},
+    targets::{DynTargetSource, Target, TargetSource, blocking},
+};
+
+struct Source {
+    inner: Box<dyn DynTargetSource>,
+    /// Targets of the last successful poll, kept while the source is failing.
+    last: Option<Vec<Target>>,
+}
+
+/// Periodically polls target sources and keeps the `PID` map in sync with their union.
+///
+/// A source that fails or times out keeps contributing the targets of its last successful poll,
+/// so a transient error does not untrack its processes.
+pub struct Reconciler {
+    profiler: Arc<Mutex<Profiler>>,
+    interval: Duration,
+    sources: Vec<Source>,
+    changed: Arc<Notify>,
+}
+
+impl Reconciler {
+    /// Reconciler polling every `interval`, at least 1ms, which also bounds each poll.
+    pub fn new(profiler: Arc<Mutex<Profiler>>, interval: Duration) -> Self {
+        Self {
+            profiler,
+            interval: interval.max(MIN_PERIOD),
+            sources: Vec::new(),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn add_source<S: TargetSource>(&mut self, mut source: S) -> &mut Self {
+        source.set_change_notifier(self.changed.clone());
+        self.sources.push(Source {
+            inner: Box::new(source),
+            last: None,
+        });
+        self
+    }
+
+    /// Polls every source once and applies the union of their targets.
+    pub async fn reconcile(&mut self) -> anyhow::Result<TrackedChanges> {
+        for source in &mut self.sources {
+            match tokio::time::timeout(self.interval, source.inner.targets()).await {
+                Ok(Ok(targets)) => source.last = Some(targets),
+                Ok(Err(e)) => warn!("target source {}: {e:#}", source.inner.name()),
+                Err(_) => warn!(
+                    "target source {}: timed out after {:?}",
+                    source.inner.name(),
+                    self.interval
+                ),
+            }
+        }
+
+        // Sources are merged in registration order; the first one to set a label wins.
+        let mut targets: HashMap<u32, Target> = HashMap::new();
+        for target in self
+            .sources
+            .iter()
+            .filter_map(|s| s.last.as_ref())
+            .flatten()
+        {
+            let merged = targets
+                .entry(target.pid)
+                .or_insert_with(|| Target::new(target.pid));
+            for (key, value) in &target.labels {
+                merged
+                    .labels
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+
+        // procfs is read off the runtime, and before locking the profiler
+        let (tracked, threads) = {
+            let profiler = self.profiler.lock().unwrap_or_else(PoisonError::into_inner);
+            (profiler.tracked_pids()?, profiler.maps_threads())
+        };
+        let pids: Vec<u32> = tracked.into_iter().chain(targets.keys().copied()).collect();
+        let procs = blocking(move || Ok(ProcInfo::read(pids, threads))).await?;
+
+        self.profiler
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_targets_with(targets.into_values(), &procs)
+    }
+
+    /// Reconciles every interval, and whenever an event driven source reports a change, until
+    /// `shutdown` resolves. Failing to apply the targets, e.g. because the `PID` map is full, is
+    /// logged and retried on the next reconciliation.
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let mut ticker = Ticker::new(self.interval);
+        tokio::pin!(shutdown);
+        loop {
+            match self.reconcile().await {
+                Ok(changes) if !changes.is_empty() => debug!(
+                    "reconciled targets: added {:?}, removed {:?}, exited {:?}, recycled {:?}",
+                    changes.added, changes.removed, changes.exited, changes.recycled
+                ),
+                Ok(_) => {}
+                Err(e) => warn!("failed to apply targets: {e:#}"),
+            }
+
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                _ = ticker.tick() => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use log::debug;
+
+use crate::targets::{
+    Labels, Target, TargetSource, blocking,
+    cgroup::{CGROUP_ROOT, read_procs},
+};
+
+/// Unit name with the `.service` suffix added when no unit type is given, like `systemctl` does.
+pub fn unit_name(unit: &str) -> String {
+    const TYPES: [&str; 11] = [
+        ".service",
+        ".socket",
+        ".device",
+        ".mount",
+        ".automount",
+        ".swap",
+        ".target",
+        ".path",
+        ".timer",
+        ".slice",
+        ".scope",
+    ];
+    if TYPES.iter().any(|suffix| unit.ends_with(suffix)) {
+        unit.to_owned()
+    } else {
+        format!("{unit}.service")
+    }
+}
+
+/// Cgroup of systemd unit `unit` in the cgroup v2 hierarchy mounted at `root`, or `None` if the
+/// unit is not active.
+///
+/// Slices are nested by their dash separated prefixes, e.g. `a-b.slice` is
+/// `a.slice/a-b.slice`. Other units usually live in `system.slice`; units in other slices, e.g.
+/// user services, are found by walking the hierarchy.
+pub fn unit_cgroup(root: &Path, unit: &str) -> io::Result<Option<PathBuf>> {
+    let unit = unit_name(unit);
+
+    if let Some(slice) = unit.strip_suffix(".slice") {
+        let mut path = root.to_owned();
+        if slice != "-" {
+            let mut prefix = String::new();
+            for part in slice.split('-') {
+                if !prefix.is_empty() {
+                    prefix.push('-');
+                }
+                prefix.push_str(part);
+                path.push(format!("{prefix}.slice"));
+            }
+        }
+        return Ok(path.is_dir().then_some(path));
+    }
+
+    let path = root.join("system.slice").join(&unit);
+    if path.is_dir() {
+        return Ok(Some(path));
+    }
+    find_dir(root, &unit)
+}
+
+fn find_dir(dir: &Path, name: &str) -> io::Result<Option<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // Cgroups may be removed while walking the hierarchy.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_name() == name {
+            return Ok(Some(path));
+        }
+        // Only slices and the user manager contain other units.
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if (file_name.ends_with(".slice") || file_name.starts_with("user@"))
+            && let Some(found) = find_dir(&path, name)?
+        {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// Tracks every process of the given systemd units, including those of units nested in a slice.
+///
+/// Targets are labeled with `unit`. Units that are not active are skipped.
+#[derive(Debug, Clone)]
+pub struct SystemdSource {
+    units: Vec<String>,
+    root: PathBuf,
+}
+
+impl SystemdSource {
+    /// Tracks `units`, e.g. `postgresql.service`, `postgresql` or `machine.slice`.
+    pub fn new(units: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self {
+            units: units
+                .into_iter()
+                .map(|unit| unit_name(unit.as_ref()))
+                .collect(),
+            root: PathBuf::from(CGROUP_ROOT),
+        }
+    }
+
+    /// Looks units up in the cgroup hierarchy mounted at `root` instead of [`CGROUP_ROOT`].
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+}
+
+impl TargetSource for SystemdSource {
+    fn name(&self) -> &str {
+        "systemd"
+    }
+
+    async fn targets(&mut self) -> anyhow::Result<Vec<Target>> {
+        let (root, units) = (self.root.clone(), self.units.clone());
+        blocking(move || unit_targets(&root, &units)).await
+    }
+}
+
+fn unit_targets(root: &Path, units: &[String]) -> anyhow::Result<Vec<Target>> {
+    let mut targets = Vec::new();
+    for unit in units {
+        let Some(path) =
+            unit_cgroup(root, unit).with_context(|| format!("failed to find cgroup of {unit}"))?
+        else {
+            debug!("unit {unit} is not active");
+            continue;
+        };
+        let pids =
+            read_procs(&path).with_context(|| format!("failed to read {}", path.display()))?;
+
+        let mut labels = Labels::new();
+        labels.insert("unit".to_owned(), unit.clone());
+        targets.extend(pids.into_iter().map(|pid| Target {
+            pid,
+            labels: labels.clone(),
+        }));
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_name_suffix() {
+        assert_eq!(unit_name("postgresql"), "postgresql.service");
+        assert_eq!(unit_name("postgresql.service"), "postgresql.service");
+        assert_eq!(unit_name("machine.slice"), "machine.slice");
+        assert_eq!(unit_name("session-1.scope"), "session-1.scope");
+        assert_eq!(unit_name("tmp.mount"), "tmp.mount");
+        assert_eq!(unit_name("logrotate.timer"), "logrotate.timer");
+        assert_eq!(
+            unit_name("proc-sys-fs-binfmt_misc.automount"),
+            "proc-sys-fs-binfmt_misc.automount"
+        );
+        assert_eq!(unit_name("multi-user.target"), "multi-user.target");
+        assert_eq!(unit_name("foo.bar"), "foo.bar.service");
+    }
+
+    /// Fake cgroup hierarchy with the given directories.
+    fn hierarchy(dirs: &[&str]) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        for dir in dirs {
+            fs::create_dir_all(root.path().join(dir)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn slices() {
+        let root = hierarchy(&["machine.slice", "a.slice/a-b.slice/a-b-c.slice"]);
+        let root = root.path();
+
+        assert_eq!(unit_cgroup(root, "-.slice").unwrap(), Some(root.to_owned()));
+        assert_eq!(
+            unit_cgroup(root, "machine.slice").unwrap(),
+            Some(root.join("machine.slice"))
+        );
+        assert_eq!(
+            unit_cgroup(root, "a-b.slice").unwrap(),
+            Some(root.join("a.slice/a-b.slice"))
+        );
+        assert_eq!(
+            unit_cgroup(root, "a-b-c.slice").unwrap(),
+            Some(root.join("a.slice/a-b.slice/a-b-c.slice"))
+        );
+        // not nested by its prefix
+        assert_eq!(unit_cgroup(root, "b.slice").unwrap(), None);
+        assert_eq!(unit_cgroup(root, "a-c.slice").unwrap(), None);
+    }
+
+    #[test]
+    fn units() {
+        let root = hierarchy(&[
+            "system.slice/postgresql.service",
+            "system.slice/system-getty.slice/getty@tty1.service",
+            "machine.slice/machine-qemu.scope",
+            "user.slice/user-1000.slice/user@1000.service/app.slice/pipewire.service",
+            "user.slice/user-1000.slice/session-2.scope",
+            "init.scope/nested.service",
+        ]);
+        let root = root.path();
+
+        assert_eq!(
+            unit_cgroup(root, "postgresql").unwrap(),
+            Some(root.join("system.slice/postgresql.service"))
+        );
+        assert_eq!(
+            unit_cgroup(root, "getty@tty1.service").unwrap(),
+            Some(root.join("system.slice/system-getty.slice/getty@tty1.service"))
+        );
+        assert_eq!(
+            unit_cgroup(root, "machine-qemu.scope").unwrap(),
+            Some(root.join("machine.slice/machine-qemu.scope"))
+        );
+        assert_eq!(
+            unit_cgroup(root, "pipewire").unwrap(),
+            Some(
+                root.join(
+                    "user.slice/user-1000.slice/user@1000.service/app.slice/pipewire.service"
+                )
+            )
+        );
+        assert_eq!(
+            unit_cgroup(root, "session-2.scope").unwrap(),
+            Some(root.join("user.slice/user-1000.slice/session-2.scope"))
+        );
+        // only slices and the user manager are searched
+        assert_eq!(unit_cgroup(root, "nested").unwrap(), None);
+        assert_eq!(unit_cgroup(root, "nginx").unwrap(), None);
+    }
+
+    #[test]
+    fn find_dir_of_missing_root() {
+        let root = tempfile::tempdir().unwrap();
+        let missing = root.path().join("gone.slice");
+        assert_eq!(find_dir(&missing, "postgresql.service").unwrap(), None);
+    }
+}